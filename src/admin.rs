@@ -0,0 +1,153 @@
+use crate::config::Source;
+use crate::serve::{refresh_once, State};
+use crate::utils::{safe_url, token_eq};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{info, warn};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Debug, Deserialize)]
+pub struct SourceRequest {
+    url: String,
+}
+
+fn reply(status: StatusCode, body: serde_json::Value) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+/// The error response to send back, if `authorization` does not carry the admin token.
+pub(crate) fn unauthorized(
+    state: &State,
+    authorization: Option<String>,
+) -> Option<warp::reply::Response> {
+    let token = state.config().admin_token.clone();
+    let token = match token {
+        Some(token) if !token.is_empty() => token,
+        _ => {
+            return Some(reply(
+                StatusCode::FORBIDDEN,
+                json!({ "error": "admin_token is not configured" }),
+            ))
+        }
+    };
+    match authorization
+        .as_deref()
+        .and_then(|a| a.strip_prefix("Bearer "))
+    {
+        Some(bearer) if token_eq(bearer, &token) => None,
+        _ => Some(reply(
+            StatusCode::UNAUTHORIZED,
+            json!({ "error": "invalid token" }),
+        )),
+    }
+}
+
+/// Change the configured sources, persist them and refetch in the background.
+///
+/// `update` fails with the status and body to answer with if the sources stay as they are. The
+/// overlay is written before the served config changes, so a failed save changes nothing.
+fn update_sources(
+    state: Arc<State>,
+    update: impl FnOnce(&mut Vec<Source>) -> Result<(), (StatusCode, Value)>,
+) -> warp::reply::Response {
+    let updated = state.update_config(|config| {
+        update(&mut config.sources)?;
+        config.save_sources(state.overlay()).map_err(|err| {
+            warn!("Failed: {}!", err.to_string());
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": err.to_string() }),
+            )
+        })
+    });
+    let config = match updated {
+        Ok(config) => config,
+        Err((status, body)) => return reply(status, body),
+    };
+    warn!(
+        "Sources saved to {}, they now override the sources of the config files!",
+        state.overlay().to_string_lossy()
+    );
+    tokio::spawn(async move { refresh_once(&state).await });
+    reply(StatusCode::ACCEPTED, json!({ "sources": config.sources }))
+}
+
+pub fn routes(
+    state: Arc<State>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    let with_state = warp::any().map(move || state.clone());
+    // the path comes first, a POST filter in front would answer every other GET with a 405
+    let admin = warp::path("api")
+        .and(warp::path("admin"))
+        .and(warp::post())
+        .and(with_state)
+        .and(warp::header::optional::<String>("authorization"));
+    let source_body = warp::body::content_length_limit(16 * 1024).and(warp::body::json());
+
+    // POST /api/admin/sources/add {"url": "..."}
+    let add = admin
+        .clone()
+        .and(warp::path!("sources" / "add"))
+        .and(source_body)
+        .map(
+            |state: Arc<State>, authorization: Option<String>, body: SourceRequest| {
+                if let Some(response) = unauthorized(&state, authorization) {
+                    return response;
+                }
+                if safe_url(&body.url).is_none() {
+                    return reply(
+                        StatusCode::BAD_REQUEST,
+                        json!({ "error": "url must be http or https" }),
+                    );
+                }
+                info!("Adding source {}", body.url);
+                update_sources(state, |sources| {
+                    if sources.iter().any(|s| s.url() == body.url) {
+                        return Err((
+                            StatusCode::CONFLICT,
+                            json!({ "error": "source already exists" }),
+                        ));
+                    }
+                    sources.push(Source::Url(body.url));
+                    Ok(())
+                })
+            },
+        );
+
+    // POST /api/admin/sources/remove {"url": "..."}
+    let remove = admin
+        .clone()
+        .and(warp::path!("sources" / "remove"))
+        .and(source_body)
+        .map(
+            |state: Arc<State>, authorization: Option<String>, body: SourceRequest| {
+                if let Some(response) = unauthorized(&state, authorization) {
+                    return response;
+                }
+                info!("Removing source {}", body.url);
+                update_sources(state, |sources| {
+                    let len = sources.len();
+                    sources.retain(|s| s.url() != body.url);
+                    if sources.len() == len {
+                        return Err((StatusCode::OK, json!({ "sources": sources })));
+                    }
+                    Ok(())
+                })
+            },
+        );
+
+    // POST /api/admin/refresh
+    let refresh = admin.and(warp::path!("refresh")).map(
+        |state: Arc<State>, authorization: Option<String>| {
+            if let Some(response) = unauthorized(&state, authorization) {
+                return response;
+            }
+            tokio::spawn(async move { refresh_once(&state).await });
+            reply(StatusCode::ACCEPTED, json!({ "refresh": "started" }))
+        },
+    );
+
+    add.or(remove).unify().or(refresh).unify()
+}
@@ -0,0 +1,439 @@
+use crate::admin::unauthorized;
+use crate::config::Source;
+use crate::media::{self, Media};
+use crate::rss_feed::Rss;
+use crate::serve::State;
+use chrono::{DateTime, NaiveDate, Utc};
+use rss::{Channel, Item};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Paging {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ItemsQuery {
+    since: Option<String>,
+    source: Option<String>,
+    q: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchQuery {
+    q: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MarkQuery {
+    value: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ItemEntry<'a> {
+    date: String,
+    source: &'a str,
+    source_link: &'a str,
+    #[serde(flatten)]
+    item: &'a Item,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media: Option<Media>,
+}
+
+fn page<T: Serialize>(entries: Vec<T>, offset: Option<usize>, limit: Option<usize>) -> Value {
+    let total = entries.len();
+    let offset = offset.unwrap_or(0);
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let entries: Vec<T> = entries.into_iter().skip(offset).take(limit).collect();
+    json!({
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "entries": entries,
+    })
+}
+
+/// Accepts either an RFC 3339 timestamp or a `%Y-%m-%d` date.
+fn parse_since(since: &str) -> Option<DateTime<Utc>> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(since) {
+        return Some(datetime.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(since, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|datetime| datetime.and_utc())
+}
+
+pub fn days(rss: &Rss, paging: &Paging) -> Value {
+    let days = rss
+        .merged_days()
+        .iter()
+        .map(|day| {
+            json!({
+                "date": day.date(),
+                "datetime": day.datetime,
+                "sources": day.channels.len(),
+                "items": day.channels.iter().map(|c| c.items.len()).sum::<usize>(),
+            })
+        })
+        .collect();
+    page(days, paging.offset, paging.limit)
+}
+
+pub fn day(rss: &Rss, sources: &[Source], date: &str) -> Option<Value> {
+    let day = rss.merged_days().into_iter().find(|d| d.date() == date)?;
+    let mut value = serde_json::to_value(&day).ok()?;
+    media::annotate_day(&mut value, &day, sources, Some(media::API_ROUTE));
+    Some(value)
+}
+
+pub fn sources(rss: &Rss) -> Value {
+    let mut sources: Vec<(&Channel, usize, DateTime<Utc>)> = vec![];
+    for day in &rss.days {
+        for channel in &day.channels {
+            match sources.iter_mut().find(|(c, _, _)| c.link == channel.link) {
+                None => sources.push((channel, channel.items.len(), day.datetime)),
+                Some((_, items, updated)) => {
+                    *items += channel.items.len();
+                    *updated = (*updated).max(day.datetime);
+                }
+            }
+        }
+    }
+    let sources: Vec<Value> = sources
+        .into_iter()
+        .map(|(channel, items, updated)| {
+            json!({
+                "title": channel.title,
+                "link": channel.link,
+                "description": channel.description,
+                "items": items,
+                "updated": updated,
+            })
+        })
+        .collect();
+    json!(sources)
+}
+
+pub fn items(rss: &Rss, sources: &[Source], query: &ItemsQuery) -> Result<Value, String> {
+    let since = match &query.since {
+        None => None,
+        Some(since) => {
+            Some(parse_since(since).ok_or_else(|| format!("invalid since '{}'", since))?)
+        }
+    };
+    let q = query.q.as_ref().map(|q| q.to_lowercase());
+
+    let mut entries = vec![];
+    for day in &rss.days {
+        for channel in &day.channels {
+            if let Some(source) = &query.source {
+                if source != &channel.link && source != &channel.title {
+                    continue;
+                }
+            }
+            for item in &channel.items {
+                if let Some(since) = since {
+                    let published = item
+                        .pub_date
+                        .as_deref()
+                        .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+                        .map(|d| d.with_timezone(&Utc))
+                        .unwrap_or(day.datetime);
+                    if published < since {
+                        continue;
+                    }
+                }
+                if let Some(q) = &q {
+                    let matched = [&item.title, &item.description].iter().any(
+                        |field| matches!(field, Some(text) if text.to_lowercase().contains(q)),
+                    );
+                    if !matched {
+                        continue;
+                    }
+                }
+                entries.push(ItemEntry {
+                    date: day.date(),
+                    source: &channel.title,
+                    source_link: &channel.link,
+                    item,
+                    media: media::served(channel, item, sources),
+                });
+            }
+        }
+    }
+    Ok(page(entries, query.offset, query.limit))
+}
+
+pub fn status(state: &State) -> Value {
+    let rss = state.rss();
+    let items: usize = rss
+        .days
+        .iter()
+        .flat_map(|d| &d.channels)
+        .map(|c| c.items.len())
+        .sum();
+    json!({
+        "site_title": rss.site_title,
+        "project_name": rss.project_name,
+        "project_version": rss.project_version,
+        "days": rss.days.len(),
+        "sources": sources(&rss).as_array().map_or(0, |s| s.len()),
+        "items": items,
+        "refreshed": state.refreshed(),
+        "open_marks": state.config().serve.open_marks,
+    })
+}
+
+/// Set or toggle the `read` or `star` mark of item `id`.
+pub fn mark(state: &State, id: &str, mark: &str, value: Option<bool>) -> warp::reply::Response {
+    let result = state.update_marks(|marks, rss| match mark {
+        "read" => {
+            marks.set_read(id, value.unwrap_or(!marks.is_read(id)));
+            true
+        }
+        "star" => marks.set_starred(rss, id, value.unwrap_or(!marks.is_starred(id))),
+        _ => false,
+    });
+    match result {
+        Ok(true) => {}
+        Ok(false) if mark == "star" => {
+            return error(StatusCode::NOT_FOUND, &format!("no item '{}'", id))
+        }
+        Ok(false) => return error(StatusCode::NOT_FOUND, &format!("no mark '{}'", mark)),
+        Err(err) => return error(StatusCode::INTERNAL_SERVER_ERROR, &err),
+    }
+    let marks = state.marks();
+    warp::reply::json(&json!({
+        "id": id,
+        "is_read": marks.is_read(id),
+        "is_starred": marks.is_starred(id),
+    }))
+    .into_response()
+}
+
+fn error(status: StatusCode, message: &str) -> warp::reply::Response {
+    warp::reply::with_status(warp::reply::json(&json!({ "error": message })), status)
+        .into_response()
+}
+
+pub fn routes(
+    state: Arc<State>,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    let with_state = warp::any().map(move || state.clone());
+    let api = warp::get().and(warp::path("api"));
+
+    // GET /api/days?offset=&limit=
+    let days_route = api
+        .and(warp::path("days"))
+        .and(warp::path::end())
+        .and(with_state.clone())
+        .and(warp::query::<Paging>())
+        .map(|state: Arc<State>, paging: Paging| {
+            warp::reply::json(&days(&state.rss(), &paging)).into_response()
+        });
+
+    // GET /api/days/{date}
+    let day_route = api
+        .and(warp::path("days"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_state.clone())
+        .map(|date: String, state: Arc<State>| {
+            match day(&state.rss(), &state.config().sources, &date) {
+                Some(day) => warp::reply::json(&day).into_response(),
+                None => error(StatusCode::NOT_FOUND, &format!("no day '{}'", date)),
+            }
+        });
+
+    // GET /api/sources
+    let sources_route = api
+        .and(warp::path("sources"))
+        .and(warp::path::end())
+        .and(with_state.clone())
+        .map(|state: Arc<State>| warp::reply::json(&sources(&state.rss())).into_response());
+
+    // GET /api/items?since=&source=&q=&offset=&limit=
+    let items_route = api
+        .and(warp::path("items"))
+        .and(warp::path::end())
+        .and(with_state.clone())
+        .and(warp::query::<ItemsQuery>())
+        .map(|state: Arc<State>, query: ItemsQuery| {
+            match items(&state.rss(), &state.config().sources, &query) {
+                Ok(items) => warp::reply::json(&items).into_response(),
+                Err(err) => error(StatusCode::BAD_REQUEST, &err),
+            }
+        });
+
+    // GET /api/search?q=&offset=&limit=
+    let search_route = api
+        .and(warp::path("search"))
+        .and(warp::path::end())
+        .and(with_state.clone())
+        .and(warp::query::<SearchQuery>())
+        .map(|state: Arc<State>, query: SearchQuery| {
+            let search = state.search_index();
+            let hits = search.search(query.q.as_deref().unwrap_or_default());
+            warp::reply::json(&page(hits, query.offset, query.limit)).into_response()
+        });
+
+    // POST /api/items/{id}/read?value=, /api/items/{id}/star?value=, requires admin_token
+    // unless serve.open_marks is set
+    let mark_route = warp::path("api")
+        .and(warp::path("items"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_state.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<MarkQuery>())
+        .map(
+            |id: String,
+             name: String,
+             state: Arc<State>,
+             authorization: Option<String>,
+             query: MarkQuery| {
+                if !state.config().serve.open_marks {
+                    if let Some(response) = unauthorized(&state, authorization) {
+                        return response;
+                    }
+                }
+                mark(&state, &id, &name, query.value)
+            },
+        );
+
+    // GET /api/status
+    let status_route = api
+        .and(warp::path("status"))
+        .and(warp::path::end())
+        .and(with_state)
+        .map(|state: Arc<State>| warp::reply::json(&status(&state)).into_response());
+
+    days_route
+        .or(day_route)
+        .unify()
+        .or(sources_route)
+        .unify()
+        .or(items_route)
+        .unify()
+        .or(search_route)
+        .unify()
+        .or(mark_route)
+        .unify()
+        .or(status_route)
+        .unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{FilterConfig, MediaMode};
+    use crate::rss_feed::DailyRss;
+    use rss::{ChannelBuilder, EnclosureBuilder, ItemBuilder};
+
+    fn rss() -> Rss {
+        let item = |title: &str| ItemBuilder::default().title(title.to_string()).build();
+        let mut podcast = item("Language Models");
+        podcast.set_enclosure(
+            EnclosureBuilder::default()
+                .url("https://cdn.example.com/ep1.mp3")
+                .mime_type("audio/mpeg")
+                .build(),
+        );
+        let channel = ChannelBuilder::default()
+            .title("arXiv cs.CL")
+            .link("https://export.arxiv.org/rss/cs.CL")
+            .items(vec![item("Attention Is All You Need"), podcast])
+            .build();
+        Rss {
+            days: vec![DailyRss {
+                datetime: Utc::now(),
+                channels: vec![channel],
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_days_merged() {
+        let mut rss = rss();
+        let later = rss.days[0].clone();
+        rss.days.push(later);
+        let page = days(&rss, &Paging::default());
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["entries"][0]["sources"], 2);
+        assert_eq!(page["entries"][0]["items"], 4);
+        let date = rss.days[0].date();
+        let day = day(&rss, &[], &date).unwrap();
+        assert_eq!(day["channels"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_items_filter() {
+        let rss = rss();
+        let query = ItemsQuery {
+            q: Some("attention".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(items(&rss, &[], &query).unwrap()["total"], 1);
+
+        let query = ItemsQuery {
+            source: Some("arXiv cs.CL".to_string()),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let page = items(&rss, &[], &query).unwrap();
+        assert_eq!(page["total"], 2);
+        assert_eq!(page["entries"].as_array().unwrap().len(), 1);
+        assert!(page["entries"][0].get("media").is_none());
+
+        let query = ItemsQuery {
+            since: Some("yesterday".to_string()),
+            ..Default::default()
+        };
+        assert!(items(&rss, &[], &query).is_err());
+    }
+
+    #[test]
+    fn test_items_media() {
+        let rss = rss();
+        let page = items(&rss, &[], &Default::default()).unwrap();
+        let media = &page["entries"][1]["media"];
+        assert_eq!(media["kind"], "audio");
+        assert_eq!(media["src"], "https://cdn.example.com/ep1.mp3");
+
+        let sources = [Source::Detailed {
+            url: "https://export.arxiv.org/rss/cs.CL".to_string(),
+            full_text: false,
+            media: MediaMode::Proxy,
+            filters: FilterConfig::default(),
+        }];
+        let page = items(&rss, &sources, &Default::default()).unwrap();
+        assert_eq!(
+            page["entries"][1]["media"]["src"],
+            "/media?url=https%3A%2F%2Fcdn.example.com%2Fep1.mp3"
+        );
+        let date = rss.days[0].date();
+        let day = day(&rss, &sources, &date).unwrap();
+        let media = &day["channels"][0]["items"][1]["media"];
+        assert_eq!(media["kind"], "audio");
+        assert_eq!(
+            media["src"],
+            "/media?url=https%3A%2F%2Fcdn.example.com%2Fep1.mp3"
+        );
+    }
+}
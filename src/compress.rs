@@ -0,0 +1,217 @@
+use crate::utils::fnv1a;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use warp::http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY};
+use warp::http::response::Parts;
+use warp::http::{Response, StatusCode};
+use warp::hyper::body::{to_bytes, Body, Bytes};
+
+/// Bodies smaller than this are not worth compressing.
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    // event streams never end, buffering them would hang the response
+    if content_type.starts_with("text/event-stream") {
+        return false;
+    }
+    content_type.starts_with("text/")
+        || content_type.contains("json")
+        || content_type.contains("javascript")
+        || content_type.contains("xml")
+}
+
+/// Pick brotli over gzip when the client accepts both, ignoring codings with `q=0`.
+fn choose_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let accepted: Vec<&str> = accept_encoding
+        .split(',')
+        .filter_map(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next()?;
+            let refused =
+                parts.any(|p| p.strip_prefix("q=").and_then(|q| q.parse().ok()) == Some(0.0));
+            if refused {
+                None
+            } else {
+                Some(name)
+            }
+        })
+        .collect();
+    if accepted.contains(&"br") {
+        Some(Encoding::Brotli)
+    } else if accepted.contains(&"gzip") {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+fn encode(encoding: Encoding, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut encoded = vec![];
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                writer.write_all(body)?;
+            }
+            Ok(encoded)
+        }
+        Encoding::Gzip => {
+            let mut writer = GzEncoder::new(vec![], Compression::default());
+            writer.write_all(body)?;
+            writer.finish()
+        }
+    }
+}
+
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// A body with its hash and the encodings of it made so far.
+///
+/// A response carrying one in its extensions is answered from it by `negotiate`, so pages
+/// served many times are hashed and compressed once.
+#[derive(Debug)]
+pub struct Encoded {
+    body: Bytes,
+    hash: u64,
+    variants: Mutex<HashMap<Encoding, Bytes>>,
+}
+
+impl Encoded {
+    pub fn new(body: Bytes) -> Encoded {
+        Encoded {
+            hash: fnv1a(&body),
+            body,
+            variants: Default::default(),
+        }
+    }
+
+    pub fn body(&self) -> &Bytes {
+        &self.body
+    }
+
+    fn variant(&self, encoding: Encoding) -> std::io::Result<Bytes> {
+        if let Some(encoded) = self.variants.lock().unwrap().get(&encoding) {
+            return Ok(encoded.clone());
+        }
+        let encoded = Bytes::from(encode(encoding, &self.body)?);
+        self.variants
+            .lock()
+            .unwrap()
+            .insert(encoding, encoded.clone());
+        Ok(encoded)
+    }
+
+    fn respond(
+        &self,
+        mut parts: Parts,
+        accept_encoding: Option<&str>,
+        if_none_match: Option<&str>,
+    ) -> Response<Body> {
+        let encoding = if self.body.len() < MIN_COMPRESS_SIZE {
+            None
+        } else {
+            accept_encoding.and_then(choose_encoding)
+        };
+
+        // the same content in another encoding is a different representation
+        let etag = match encoding {
+            None => format!("\"{:016x}\"", self.hash),
+            Some(encoding) => format!("\"{:016x}-{}\"", self.hash, encoding.name()),
+        };
+        parts
+            .headers
+            .insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+        if let Ok(value) = HeaderValue::from_str(&etag) {
+            parts.headers.insert(ETAG, value);
+        }
+
+        if matches!(if_none_match, Some(tags) if etag_matches(tags, &etag)) {
+            parts.status = StatusCode::NOT_MODIFIED;
+            parts.headers.remove(CONTENT_LENGTH);
+            return Response::from_parts(parts, Body::empty());
+        }
+
+        let body = match encoding.map(|e| (e, self.variant(e))) {
+            Some((encoding, Ok(encoded))) => {
+                parts
+                    .headers
+                    .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+                encoded
+            }
+            _ => self.body.clone(),
+        };
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        Response::from_parts(parts, Body::from(body))
+    }
+}
+
+/// Add a strong ETag to compressible `200` responses, answer `If-None-Match` with `304`
+/// and compress the body with the best encoding the client accepts.
+pub async fn negotiate(
+    accept_encoding: Option<String>,
+    if_none_match: Option<String>,
+    response: Response<Body>,
+) -> Response<Body> {
+    let compressible = response.status() == StatusCode::OK
+        && !response.headers().contains_key(CONTENT_ENCODING)
+        && response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(is_compressible);
+    if !compressible {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let encoded = match parts.extensions.remove::<Arc<Encoded>>() {
+        Some(encoded) => encoded,
+        None => match to_bytes(body).await {
+            Ok(body) => Arc::new(Encoded::new(body)),
+            Err(_) => {
+                parts.status = StatusCode::INTERNAL_SERVER_ERROR;
+                parts.headers.remove(CONTENT_LENGTH);
+                return Response::from_parts(parts, Body::empty());
+            }
+        },
+    };
+    encoded.respond(parts, accept_encoding.as_deref(), if_none_match.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_encoding() {
+        assert_eq!(choose_encoding("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(choose_encoding("gzip, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(choose_encoding("identity"), None);
+        assert!(etag_matches("W/\"a\", \"b\"", "\"a\""));
+    }
+}
@@ -6,11 +6,14 @@ use figment::{
 };
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 use tracing::info;
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub(crate) minify: bool,
+    pub(crate) allow_raw_html: bool,
     pub(crate) cache_max_days: i64,
     pub(crate) site_title: String,
     pub(crate) target_dir: String,
@@ -18,15 +21,179 @@ pub struct Config {
     pub(crate) templates_dir: String,
     pub(crate) proxy: Option<String>,
     pub(crate) cache_url: Option<String>,
+    pub(crate) admin_token: Option<String>,
+    /// Read `admin_token` from this environment variable instead of the config files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) admin_token_env: Option<String>,
+    /// Read `admin_token` from this file instead of the config files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) admin_token_file: Option<String>,
     pub(crate) target_name: Option<String>,
-    pub(crate) sources: Vec<String>,
+    pub(crate) sources: Vec<Source>,
+    pub(crate) filters: FilterConfig,
     pub(crate) scripts: HashMap<String, String>,
+    pub(crate) hooks: Vec<String>,
+    pub(crate) rhai: RhaiConfig,
+    pub(crate) mail: Option<MailConfig>,
+    pub(crate) serve: ServeConfig,
+}
+
+/// Keywords match case-insensitively anywhere in the title, description, author or categories,
+/// regexes are case-insensitive too and match each of those fields on its own.
+///
+/// An item is kept if it matches any `include` rule (or there are none) and no `exclude` rule.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FilterConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) exclude: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) include_regex: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) exclude_regex: Vec<String>,
+}
+
+/// A feed url, or a table with the url and the options of just this feed.
+///
+/// With `full_text`, items without content get the main text of the page they link to, with
+/// `media = "proxy"` the enclosures of its items are played through `serve` instead of linked.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Source {
+    Url(String),
+    Detailed {
+        url: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        full_text: bool,
+        #[serde(default, skip_serializing_if = "MediaMode::is_link")]
+        media: MediaMode,
+        #[serde(flatten)]
+        filters: FilterConfig,
+    },
+}
+
+/// How the default template plays the enclosures of a source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaMode {
+    /// Straight from the feed's host.
+    #[default]
+    Link,
+    /// Through the `/media` route of `serve`, for hosts that block hotlinking or plain HTTP ones.
+    Proxy,
+}
+
+impl MediaMode {
+    fn is_link(&self) -> bool {
+        *self == MediaMode::Link
+    }
+}
+
+impl Source {
+    pub fn url(&self) -> &str {
+        match self {
+            Source::Url(url) => url,
+            Source::Detailed { url, .. } => url,
+        }
+    }
+
+    pub fn full_text(&self) -> bool {
+        match self {
+            Source::Url(_) => false,
+            Source::Detailed { full_text, .. } => *full_text,
+        }
+    }
+
+    pub fn media(&self) -> MediaMode {
+        match self {
+            Source::Url(_) => MediaMode::Link,
+            Source::Detailed { media, .. } => *media,
+        }
+    }
+
+    pub fn filters(&self) -> Option<&FilterConfig> {
+        match self {
+            Source::Url(_) => None,
+            Source::Detailed { filters, .. } => Some(filters),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ServeConfig {
+    pub(crate) tls_cert: Option<String>,
+    pub(crate) tls_key: Option<String>,
+    pub(crate) basic_auth: Option<String>,
+    pub(crate) token: Option<String>,
+    pub(crate) unix_socket: Option<String>,
+    /// Let every client that gets past `basic_auth` and `token` change read and starred state,
+    /// otherwise it takes the `admin_token`.
+    #[serde(default)]
+    pub(crate) open_marks: bool,
+}
+
+/// Limits of every single run of a script helper or hook, `0` lifts a limit.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RhaiConfig {
+    pub(crate) max_operations: u64,
+    pub(crate) max_call_levels: usize,
+    pub(crate) max_string_size: usize,
+    pub(crate) max_array_size: usize,
+    pub(crate) max_map_size: usize,
+    pub(crate) timeout_ms: u64,
+}
+
+impl Default for RhaiConfig {
+    fn default() -> RhaiConfig {
+        RhaiConfig {
+            max_operations: 1_000_000,
+            max_call_levels: 64,
+            max_string_size: 4 * 1024 * 1024,
+            max_array_size: 100_000,
+            max_map_size: 100_000,
+            timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTls {
+    None,
+    Starttls,
+    Tls,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MailConfig {
+    pub(crate) smtp_host: String,
+    pub(crate) smtp_port: Option<u16>,
+    #[serde(default = "default_mail_tls")]
+    pub(crate) tls: MailTls,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    /// Read `password` from this environment variable instead of the config files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) password_env: Option<String>,
+    /// Read `password` from this file instead of the config files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) password_file: Option<String>,
+    pub(crate) subject: Option<String>,
+    pub(crate) from: String,
+    pub(crate) to: Vec<String>,
+}
+
+fn default_mail_tls() -> MailTls {
+    MailTls::Starttls
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             minify: false,
+            allow_raw_html: false,
             cache_max_days: 0,
             site_title: crate_name!().to_string(),
             target_dir: "target".to_string(),
@@ -34,19 +201,105 @@ impl Default for Config {
             templates_dir: "includes".to_string(),
             proxy: None,
             cache_url: None,
+            admin_token: None,
+            admin_token_env: None,
+            admin_token_file: None,
             target_name: None,
             sources: Default::default(),
+            filters: Default::default(),
             scripts: Default::default(),
+            hooks: Default::default(),
+            rhai: Default::default(),
+            mail: None,
+            serve: Default::default(),
         }
     }
 }
 
+/// A secret from the environment variable `env` or the file `file` if either is set, else `value`.
+fn secret(
+    name: &str,
+    value: Option<String>,
+    env: &Option<String>,
+    file: &Option<String>,
+) -> std::result::Result<Option<String>, String> {
+    if let Some(var) = env {
+        let value = std::env::var(var).map_err(|err| format!("{name}_env {var}: {err}"))?;
+        return Ok(Some(value));
+    }
+    if let Some(path) = file {
+        let value = fs::read_to_string(path).map_err(|err| format!("{name}_file {path}: {err}"))?;
+        return Ok(Some(value.trim_end().to_string()));
+    }
+    Ok(value)
+}
+
+/// Written by the admin endpoints of `serve`, so the user's own config files keep their comments.
+///
+/// Once written, its `sources` replace those of `Config.yaml` and `Config.toml`.
+pub(crate) const OVERLAY_FILE: &str = "Config.overlay.toml";
+
 impl Config {
     pub fn new() -> Result<Config> {
         info!("Loading config!");
-        Figment::from(Serialized::defaults(Config::default()))
+        let mut config: Config = Figment::from(Serialized::defaults(Config::default()))
             .merge(Yaml::file("Config.yaml"))
             .merge(Toml::file("Config.toml"))
-            .extract()
+            .merge(Toml::file(OVERLAY_FILE))
+            .extract()?;
+        config.admin_token = secret(
+            "admin_token",
+            config.admin_token.take(),
+            &config.admin_token_env,
+            &config.admin_token_file,
+        )?;
+        if let Some(mail) = config.mail.as_mut() {
+            mail.password = secret(
+                "password",
+                mail.password.take(),
+                &mail.password_env,
+                &mail.password_file,
+            )?;
+        }
+        Ok(config)
+    }
+
+    /// Persist `sources` into the overlay file `path`, keeping any other keys already in it.
+    pub fn save_sources(&self, path: &Path) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let mut overlay: toml::Table = match fs::read_to_string(path) {
+            Ok(content) => content.parse()?,
+            Err(_) => Default::default(),
+        };
+        overlay.insert("sources".to_string(), toml::Value::try_from(&self.sources)?);
+        fs::write(path, toml::to_string(&overlay)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret() {
+        let inline = Some("inline".to_string());
+        assert_eq!(
+            secret("password", inline.clone(), &None, &None).unwrap(),
+            inline
+        );
+
+        std::env::set_var("NOTFEED_TEST_SECRET", "from-env");
+        let env = Some("NOTFEED_TEST_SECRET".to_string());
+        let value = secret("password", inline.clone(), &env, &None).unwrap();
+        assert_eq!(value.as_deref(), Some("from-env"));
+        let missing = Some("NOTFEED_TEST_SECRET_MISSING".to_string());
+        assert!(secret("password", None, &missing, &None).is_err());
+
+        let path = std::env::temp_dir().join("notfeed-secret");
+        fs::write(&path, "from-file\n").unwrap();
+        let file = Some(path.to_string_lossy().to_string());
+        let value = secret("password", inline, &None, &file).unwrap();
+        assert_eq!(value.as_deref(), Some("from-file"));
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,155 @@
+use reqwest::{Client, Url};
+use rss::Channel;
+use scraper::{Html, Selector};
+use tracing::info;
+
+/// Feed types a site may announce, best first; only RSS can be read.
+const FEED_TYPES: &[&str] = &[
+    "application/rss+xml",
+    "application/atom+xml",
+    "application/feed+json",
+    "application/json",
+];
+
+/// Where sites that announce no feed usually keep one anyway.
+const COMMON_PATHS: &[&str] = &[
+    "/feed",
+    "/rss",
+    "/feed.xml",
+    "/rss.xml",
+    "/atom.xml",
+    "/index.xml",
+];
+
+/// A feed a website may have, `kind` is the announced type or `"guess"`.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub(crate) url: String,
+    pub(crate) kind: String,
+    pub(crate) title: Option<String>,
+}
+
+/// Feeds announced by `<link rel="alternate">` in `html`, best type first, then the common paths.
+pub fn candidates(base: &Url, html: &str) -> Vec<Candidate> {
+    let document = Html::parse_document(html);
+    let links = Selector::parse(r#"link[rel~="alternate"][href][type]"#).unwrap();
+
+    let mut announced: Vec<(usize, Candidate)> = document
+        .select(&links)
+        .filter_map(|link| {
+            let element = link.value();
+            let kind = element.attr("type")?.trim().to_lowercase();
+            let rank = FEED_TYPES.iter().position(|t| *t == kind)?;
+            let url = base.join(element.attr("href")?.trim()).ok()?;
+            let title = element.attr("title").map(|t| t.trim().to_string());
+            Some((
+                rank,
+                Candidate {
+                    url: url.to_string(),
+                    kind,
+                    title,
+                },
+            ))
+        })
+        .collect();
+    announced.sort_by_key(|(rank, _)| *rank);
+
+    let mut candidates: Vec<Candidate> = announced.into_iter().map(|(_, c)| c).collect();
+    for path in COMMON_PATHS {
+        let url = match base.join(path) {
+            Ok(url) => url.to_string(),
+            Err(_) => continue,
+        };
+        if !candidates.iter().any(|c| c.url == url) {
+            candidates.push(Candidate {
+                url,
+                kind: "guess".to_string(),
+                title: None,
+            });
+        }
+    }
+    candidates
+}
+
+async fn fetch_channel(client: &Client, url: &str) -> Result<Channel, Box<dyn std::error::Error>> {
+    let content = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(Channel::read_from(&content[..])?)
+}
+
+/// The first candidate of the website at `base` that is a readable feed.
+pub async fn resolve(client: &Client, base: &Url, html: &str) -> Option<(String, Channel)> {
+    for candidate in candidates(base, html) {
+        if let Ok(channel) = fetch_channel(client, &candidate.url).await {
+            return Some((candidate.url, channel));
+        }
+    }
+    None
+}
+
+/// Every candidate of `url` with whether it can be read, `url` itself if it is a feed already.
+pub async fn discover(
+    client: &Client,
+    url: &str,
+) -> Result<Vec<(Candidate, Result<(), String>)>, Box<dyn std::error::Error>> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    let base = response.url().clone();
+    let content = response.bytes().await?;
+    if let Ok(channel) = Channel::read_from(&content[..]) {
+        let candidate = Candidate {
+            url: base.to_string(),
+            kind: "application/rss+xml".to_string(),
+            title: Some(channel.title),
+        };
+        return Ok(vec![(candidate, Ok(()))]);
+    }
+
+    let html = String::from_utf8_lossy(&content);
+    let mut found = vec![];
+    for candidate in candidates(&base, &html) {
+        info!("Probing {}", candidate.url);
+        let status = fetch_channel(client, &candidate.url)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string());
+        found.push((candidate, status));
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let html = r#"<html><head>
+            <link rel="alternate" type="application/atom+xml" title="Atom" href="/atom.xml">
+            <link rel="stylesheet" type="text/css" href="/style.css">
+            <link rel="alternate" type="application/rss+xml" title="RSS" href="feed.xml">
+            <link rel="alternate" hreflang="de" href="/de/">
+        </head></html>"#;
+        let base = Url::parse("https://blog.example.com/posts/").unwrap();
+        let candidates = candidates(&base, html);
+        let urls: Vec<&str> = candidates.iter().map(|c| c.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://blog.example.com/posts/feed.xml",
+                "https://blog.example.com/atom.xml",
+                "https://blog.example.com/feed",
+                "https://blog.example.com/rss",
+                "https://blog.example.com/feed.xml",
+                "https://blog.example.com/rss.xml",
+                "https://blog.example.com/index.xml",
+            ]
+        );
+        assert_eq!(candidates[0].title.as_deref(), Some("RSS"));
+        assert_eq!(candidates[2].kind, "guess");
+    }
+}
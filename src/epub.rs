@@ -0,0 +1,402 @@
+use crate::rss_feed::Rss;
+use crate::utils::xml_escape;
+use chrono::{SecondsFormat, Utc};
+use clap::ValueEnum;
+use handlebars::Handlebars;
+use html_escape::decode_html_entities;
+use regex::{Captures, Regex};
+use reqwest::Client;
+use rss::Channel;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use tracing::{info, warn};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Split {
+    Day,
+    Source,
+}
+
+struct Chapter {
+    title: String,
+    body: String,
+}
+
+struct Image {
+    href: String,
+    media_type: String,
+    content: Vec<u8>,
+}
+
+pub async fn build_epub(
+    hbs: &Handlebars<'_>,
+    rss: &Rss,
+    split: Split,
+    client: Option<&Client>,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Rendering chapters!");
+    let mut chapters = vec![];
+    match split {
+        Split::Day => {
+            for day in rss.merged_days() {
+                let title = day.date();
+                let ctx = json!({
+                    "site_title": rss.site_title,
+                    "title": title,
+                    "datetime": day.datetime,
+                    "channels": day.channels,
+                });
+                let body = hbs.render("epub", &ctx)?;
+                chapters.push(Chapter { title, body });
+            }
+        }
+        Split::Source => {
+            for channel in merge_sources(rss) {
+                let title = channel.title.clone();
+                let ctx = json!({
+                    "site_title": rss.site_title,
+                    "title": title,
+                    "channels": [channel],
+                });
+                let body = hbs.render("epub", &ctx)?;
+                chapters.push(Chapter { title, body });
+            }
+        }
+    }
+
+    let images = match client {
+        None => vec![],
+        Some(client) => {
+            info!("Bundling images!");
+            bundle_images(&mut chapters, client).await
+        }
+    };
+
+    write_epub(rss, &chapters, &images, path)
+}
+
+/// Collect the items of every source across all days into a single channel per source.
+fn merge_sources(rss: &Rss) -> Vec<Channel> {
+    let mut channels: Vec<Channel> = vec![];
+    for day in &rss.days {
+        for channel in &day.channels {
+            match channels.iter_mut().find(|c| c.link == channel.link) {
+                None => channels.push(channel.clone()),
+                Some(merged) => {
+                    for item in &channel.items {
+                        if !merged.items.iter().any(|i| i.link == item.link) {
+                            merged.items.push(item.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    channels
+}
+
+async fn bundle_images(chapters: &mut [Chapter], client: &Client) -> Vec<Image> {
+    let re = Regex::new(r#"(<img\b[^>]*?\bsrc=")([^"]+)(")"#).unwrap();
+
+    let mut images = vec![];
+    let mut hrefs: HashMap<String, String> = HashMap::new();
+    for chapter in chapters.iter() {
+        for caps in re.captures_iter(&chapter.body) {
+            let src = caps[2].to_string();
+            if hrefs.contains_key(&src) {
+                continue;
+            }
+            let url = decode_html_entities(&src).to_string();
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                continue;
+            }
+            match fetch_image(client, &url, images.len() + 1).await {
+                Ok(image) => {
+                    hrefs.insert(src, image.href.clone());
+                    images.push(image);
+                }
+                Err(err) => {
+                    warn!("Failed: {}!", err.to_string());
+                }
+            }
+        }
+    }
+
+    for chapter in chapters.iter_mut() {
+        chapter.body = re
+            .replace_all(&chapter.body, |caps: &Captures| match hrefs.get(&caps[2]) {
+                Some(href) => format!("{}{}{}", &caps[1], href, &caps[3]),
+                None => caps[0].to_string(),
+            })
+            .to_string();
+    }
+
+    images
+}
+
+async fn fetch_image(
+    client: &Client,
+    url: &str,
+    index: usize,
+) -> Result<Image, Box<dyn std::error::Error>> {
+    info!("Fetching image from {}", url);
+    let response = client.get(url).send().await?.error_for_status()?;
+    let media_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    let extension = match media_type.as_str() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        _ => return Err(format!("unsupported image type '{}' at {}", media_type, url).into()),
+    };
+    let content = response.bytes().await?.to_vec();
+    Ok(Image {
+        href: format!("images/image-{}.{}", index, extension),
+        media_type,
+        content,
+    })
+}
+
+fn write_epub(
+    rss: &Rss,
+    chapters: &[Chapter],
+    images: &[Image],
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let modified = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    let title = xml_escape(&rss.site_title);
+
+    let mut manifest = String::new();
+    let mut spine = String::new();
+    let mut nav_points = String::new();
+    let mut nav_list = String::new();
+    for (i, chapter) in chapters.iter().enumerate() {
+        let id = format!("chapter-{}", i + 1);
+        let chapter_title = xml_escape(&chapter.title);
+        manifest += &format!(
+            r#"<item id="{id}" href="{id}.xhtml" media-type="application/xhtml+xml"/>"#,
+            id = id
+        );
+        spine += &format!(r#"<itemref idref="{}"/>"#, id);
+        nav_list += &format!(r#"<li><a href="{}.xhtml">{}</a></li>"#, id, chapter_title);
+        nav_points += &format!(
+            r#"<navPoint id="{id}" playOrder="{order}"><navLabel><text>{title}</text></navLabel><content src="{id}.xhtml"/></navPoint>"#,
+            id = id,
+            order = i + 1,
+            title = chapter_title
+        );
+    }
+    for (i, image) in images.iter().enumerate() {
+        manifest += &format!(
+            r#"<item id="image-{}" href="{}" media-type="{}"/>"#,
+            i + 1,
+            image.href,
+            image.media_type
+        );
+    }
+
+    let container = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+    <rootfiles>
+        <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+    </rootfiles>
+</container>"#;
+
+    let package = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+        <dc:identifier id="uid">urn:{name}:{modified}</dc:identifier>
+        <dc:title>{title}</dc:title>
+        <dc:language>en</dc:language>
+        <dc:creator>{name} {version}</dc:creator>
+        <meta property="dcterms:modified">{modified}</meta>
+    </metadata>
+    <manifest>
+        <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+        <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+        {manifest}
+    </manifest>
+    <spine toc="ncx">
+        <itemref idref="nav"/>
+        {spine}
+    </spine>
+</package>"#,
+        name = rss.project_name,
+        version = rss.project_version,
+        modified = modified,
+        title = title,
+        manifest = manifest,
+        spine = spine
+    );
+
+    let nav = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="en" xml:lang="en">
+<head><title>{title}</title></head>
+<body>
+    <nav epub:type="toc" id="toc">
+        <h1>{title}</h1>
+        <ol>{nav_list}</ol>
+    </nav>
+</body>
+</html>"#,
+        title = title,
+        nav_list = nav_list
+    );
+
+    let ncx = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+    <head><meta name="dtb:uid" content="urn:{name}:{modified}"/></head>
+    <docTitle><text>{title}</text></docTitle>
+    <navMap>{nav_points}</navMap>
+</ncx>"#,
+        name = rss.project_name,
+        modified = modified,
+        title = title,
+        nav_points = nav_points
+    );
+
+    let mut zip = ZipWriter::new(File::create(path)?);
+    // the mimetype entry must come first and be stored uncompressed
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.start_file("META-INF/container.xml", deflated)?;
+    zip.write_all(container.as_bytes())?;
+    zip.start_file("OEBPS/content.opf", deflated)?;
+    zip.write_all(package.as_bytes())?;
+    zip.start_file("OEBPS/nav.xhtml", deflated)?;
+    zip.write_all(nav.as_bytes())?;
+    zip.start_file("OEBPS/toc.ncx", deflated)?;
+    zip.write_all(ncx.as_bytes())?;
+    for (i, chapter) in chapters.iter().enumerate() {
+        zip.start_file(format!("OEBPS/chapter-{}.xhtml", i + 1), deflated)?;
+        zip.write_all(chapter.body.as_bytes())?;
+    }
+    for image in images {
+        zip.start_file(format!("OEBPS/{}", image.href), stored)?;
+        zip.write_all(&image.content)?;
+    }
+    zip.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::render::handlebars;
+    use crate::rss_feed::DailyRss;
+    use chrono::TimeZone;
+    use rss::{ChannelBuilder, ItemBuilder};
+    use std::io::Read;
+    use zip::ZipArchive;
+
+    #[tokio::test]
+    async fn test_build_epub() {
+        let item = |title: &str, link: &str| {
+            ItemBuilder::default()
+                .title(title.to_string())
+                .link(link.to_string())
+                .description("<p>An <b>abstract</b><br></p>".to_string())
+                .build()
+        };
+        let channel = |items| {
+            ChannelBuilder::default()
+                .title("arXiv cs.CL")
+                .link("https://export.arxiv.org/rss/cs.CL")
+                .items(items)
+                .build()
+        };
+        let day = |hours: i64, items| DailyRss {
+            datetime: Utc.with_ymd_and_hms(2024, 5, 2, 20, 0, 0).unwrap()
+                - chrono::Duration::hours(hours),
+            channels: vec![channel(items)],
+        };
+        let rss = Rss {
+            site_title: "Tom & Jerry".to_string(),
+            days: vec![
+                day(
+                    0,
+                    vec![item("Attention", "https://arxiv.org/abs/1706.03762")],
+                ),
+                // fetched earlier the same day
+                day(10, vec![item("GPT", "https://example.com/gpt")]),
+                day(
+                    24,
+                    vec![
+                        item("Attention", "https://arxiv.org/abs/1706.03762"),
+                        item("BERT", "https://arxiv.org/abs/1810.04805"),
+                    ],
+                ),
+            ],
+            ..Default::default()
+        };
+        let hbs = handlebars(&Config::default()).unwrap();
+        let dir = std::env::temp_dir().join("notfeed-epub");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("day.epub");
+        build_epub(&hbs, &rss, Split::Day, None, &path)
+            .await
+            .unwrap();
+        let mut epub = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        {
+            let mut mimetype = epub.by_index(0).unwrap();
+            assert_eq!(mimetype.name(), "mimetype");
+            assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+            let mut content = String::new();
+            mimetype.read_to_string(&mut content).unwrap();
+            assert_eq!(content, "application/epub+zip");
+        }
+        let mut package = String::new();
+        epub.by_name("OEBPS/content.opf")
+            .unwrap()
+            .read_to_string(&mut package)
+            .unwrap();
+        assert!(package.contains("<dc:title>Tom &amp; Jerry</dc:title>"));
+        assert!(package.contains(r#"<itemref idref="chapter-2"/>"#));
+        let mut chapter = String::new();
+        epub.by_name("OEBPS/chapter-1.xhtml")
+            .unwrap()
+            .read_to_string(&mut chapter)
+            .unwrap();
+        assert!(chapter.contains("<p>An <b>abstract</b><br/></p>"));
+        assert!(chapter.contains("<h1>2024-05-02</h1>") && chapter.contains("GPT"));
+        assert!(epub.by_name("OEBPS/chapter-3.xhtml").is_err());
+
+        let path = dir.join("source.epub");
+        build_epub(&hbs, &rss, Split::Source, None, &path)
+            .await
+            .unwrap();
+        let mut epub = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        assert!(epub.by_name("OEBPS/chapter-2.xhtml").is_err());
+        let mut chapter = String::new();
+        epub.by_name("OEBPS/chapter-1.xhtml")
+            .unwrap()
+            .read_to_string(&mut chapter)
+            .unwrap();
+        assert_eq!(chapter.matches("Attention").count(), 1);
+        assert!(chapter.contains("BERT") && chapter.contains("GPT"));
+    }
+}
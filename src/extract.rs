@@ -0,0 +1,275 @@
+use crate::config::Source;
+use crate::rss_feed::DailyRss;
+use crate::utils::{sanitize_html, strip_tags, xml_escape, VOID_ELEMENTS};
+use ego_tree::NodeRef;
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::{Client, Url};
+use rss::Channel;
+use scraper::node::Element;
+use scraper::{ElementRef, Html, Node, Selector};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{info, warn};
+
+lazy_static! {
+    static ref POSITIVE: Regex = Regex::new(
+        r"(?i)article|body|content|entry|hentry|main|page|post|text|blog|story"
+    )
+    .unwrap();
+    static ref NEGATIVE: Regex = Regex::new(
+        r"(?i)comment|meta|footer|footnote|sidebar|sponsor|\bads?\b|share|social|nav|menu|related|promo|widget|banner|masthead|popup|hidden"
+    )
+    .unwrap();
+}
+
+/// Never part of an article.
+const UNLIKELY_TAGS: &[&str] = &[
+    "aside", "button", "footer", "form", "header", "iframe", "nav", "noscript", "script", "style",
+    "svg",
+];
+
+/// The attributes kept in extracted HTML, `href` and `src` are made absolute.
+const KEPT_ATTRS: &[&str] = &["href", "src", "alt", "title"];
+
+/// Text shorter than this is too little to call an article.
+const MIN_ARTICLE_LEN: usize = 140;
+
+fn class_weight(element: &Element) -> f64 {
+    let mut weight = 0.0;
+    for value in [element.attr("class"), element.id()].into_iter().flatten() {
+        if NEGATIVE.is_match(value) {
+            weight -= 25.0;
+        }
+        if POSITIVE.is_match(value) {
+            weight += 25.0;
+        }
+    }
+    weight
+}
+
+fn tag_weight(name: &str) -> f64 {
+    match name {
+        "article" => 10.0,
+        "div" => 5.0,
+        "pre" | "td" | "blockquote" => 3.0,
+        "address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" | "form" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    }
+}
+
+fn is_unlikely(element: &Element) -> bool {
+    let boilerplate = [element.attr("class"), element.id()]
+        .into_iter()
+        .flatten()
+        .any(|value| NEGATIVE.is_match(value) && !POSITIVE.is_match(value));
+    boilerplate || UNLIKELY_TAGS.contains(&element.name())
+}
+
+fn text_len(element: ElementRef) -> usize {
+    element.text().map(|text| text.trim().chars().count()).sum()
+}
+
+/// Share of the text of `element` that sits in links.
+fn link_density(element: ElementRef) -> f64 {
+    let links = Selector::parse("a").unwrap();
+    let total = text_len(element);
+    if total == 0 {
+        return 0.0;
+    }
+    let linked: usize = element.select(&links).map(text_len).sum();
+    linked as f64 / total as f64
+}
+
+/// The main content of an article page, readability style: paragraphs score their parent and
+/// grandparent by length and commas, and the best scored element less its link share wins,
+/// together with siblings that scored well too.
+pub fn extract(html: &str, base: Option<&Url>) -> Option<String> {
+    let document = Html::parse_document(html);
+    let paragraphs = Selector::parse("p, pre, td").unwrap();
+
+    let mut scores: HashMap<_, f64> = HashMap::new();
+    for paragraph in document.select(&paragraphs) {
+        let unlikely = paragraph
+            .ancestors()
+            .filter_map(ElementRef::wrap)
+            .any(|ancestor| is_unlikely(ancestor.value()));
+        if unlikely {
+            continue;
+        }
+        let text: String = paragraph.text().collect();
+        let len = text.trim().chars().count();
+        if len < 25 {
+            continue;
+        }
+        let score = 1.0 + text.matches(',').count() as f64 + (len / 100).min(3) as f64;
+        let ancestors = paragraph.ancestors().filter_map(ElementRef::wrap).take(2);
+        for (level, ancestor) in ancestors.enumerate() {
+            let entry = scores.entry(ancestor.id()).or_insert_with(|| {
+                tag_weight(ancestor.value().name()) + class_weight(ancestor.value())
+            });
+            *entry += if level == 0 { score } else { score / 2.0 };
+        }
+    }
+
+    let element = |id| document.tree.get(id).and_then(ElementRef::wrap);
+    let scored: HashMap<_, f64> = scores
+        .into_iter()
+        .filter_map(|(id, score)| Some((id, score * (1.0 - link_density(element(id)?)))))
+        .collect();
+    let (&top_id, &top_score) = scored
+        .iter()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(Ordering::Equal))?;
+    let top = element(top_id)?;
+
+    let threshold = (top_score * 0.2).max(10.0);
+    let mut parts = vec![];
+    match top.parent() {
+        Some(parent) => {
+            for sibling in parent.children() {
+                if sibling.id() == top_id {
+                    parts.extend(top.children());
+                } else if scored.get(&sibling.id()).is_some_and(|s| *s >= threshold) {
+                    parts.push(sibling);
+                }
+            }
+        }
+        None => parts.extend(top.children()),
+    }
+
+    let mut res = String::new();
+    for part in parts {
+        write_clean(part, base, &mut res);
+    }
+    let res = sanitize_html(&res);
+    if strip_tags(&res).chars().count() < MIN_ARTICLE_LEN {
+        return None;
+    }
+    Some(res)
+}
+
+fn write_clean(node: NodeRef<Node>, base: Option<&Url>, res: &mut String) {
+    match node.value() {
+        Node::Text(text) => res.push_str(&xml_escape(text)),
+        Node::Element(element) => {
+            if is_unlikely(element) {
+                return;
+            }
+            let name = element.name();
+            res.push('<');
+            res.push_str(name);
+            for (key, value) in element.attrs() {
+                if !KEPT_ATTRS.contains(&key) {
+                    continue;
+                }
+                let value = match (key, base) {
+                    ("href" | "src", Some(base)) => base
+                        .join(value)
+                        .map(String::from)
+                        .unwrap_or_else(|_| value.to_string()),
+                    _ => value.to_string(),
+                };
+                res.push_str(&format!(" {}=\"{}\"", key, xml_escape(&value)));
+            }
+            res.push('>');
+            if VOID_ELEMENTS.contains(&name) {
+                return;
+            }
+            for child in node.children() {
+                write_clean(child, base, res);
+            }
+            res.push_str(&format!("</{}>", name));
+        }
+        _ => {}
+    }
+}
+
+async fn fetch(client: &Client, link: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let url = Url::parse(link)?;
+    let response = client
+        .get(url.clone())
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await?
+        .error_for_status()?;
+    let html = response.text().await?;
+    Ok(extract(&html, Some(&url)).ok_or("no article found")?)
+}
+
+/// Give items of `full_text` sources that come without content the text of the page they link to,
+/// items already in `cached` keep the content extracted back then.
+pub async fn fill_content(
+    channels: &mut [Channel],
+    sources: &[Source],
+    cached: &[DailyRss],
+    client: &Client,
+) {
+    let full_text: Vec<&str> = sources
+        .iter()
+        .filter(|s| s.full_text())
+        .map(|s| s.url())
+        .collect();
+    if full_text.is_empty() {
+        return;
+    }
+    let known: HashMap<&str, &str> = cached
+        .iter()
+        .flat_map(|day| &day.channels)
+        .filter(|channel| full_text.contains(&channel.link.as_str()))
+        .flat_map(|channel| &channel.items)
+        .filter_map(|item| Some((item.link.as_deref()?, item.content.as_deref()?)))
+        .collect();
+
+    for channel in channels {
+        if !full_text.contains(&channel.link.as_str()) {
+            continue;
+        }
+        for item in &mut channel.items {
+            if item
+                .content
+                .as_deref()
+                .is_some_and(|c| !c.trim().is_empty())
+            {
+                continue;
+            }
+            let link = match item.link.as_deref() {
+                Some(link) if !link.is_empty() => link,
+                _ => continue,
+            };
+            if let Some(content) = known.get(link) {
+                item.content = Some(content.to_string());
+                continue;
+            }
+            info!("Extracting full text of {}", link);
+            match fetch(client, link).await {
+                Ok(content) => item.content = Some(content),
+                Err(err) => warn!("Failed: {} for {}!", err, link),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        let html = include_str!("../tests/fixtures/article.html");
+        let base = Url::parse("https://blog.example.com/posts/attention/").unwrap();
+        let content = extract(html, Some(&base)).unwrap();
+
+        assert!(content.contains("The dominant sequence transduction models"));
+        assert!(content.contains("Multi-head attention allows the model"));
+        assert!(content.contains("src=\"https://blog.example.com/posts/attention/figure-1.png\""));
+        assert!(!content.contains("Subscribe to our newsletter"));
+        assert!(!content.contains("Related posts"));
+        assert!(!content.contains("Leave a comment"));
+        assert!(!content.contains("tracking()"));
+
+        let html = include_str!("../tests/fixtures/index.html");
+        assert!(extract(html, None).is_none());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::config::FilterConfig;
+use regex::{Regex, RegexBuilder};
+use rss::Item;
+
+enum Rule {
+    Keyword(String),
+    Regex(Regex),
+}
+
+impl Rule {
+    /// Whether any of the fields matches, each on its own so `^` and `$` anchor to the field.
+    fn matches(&self, fields: &[(&str, String)]) -> bool {
+        fields.iter().any(|(text, lowercase)| match self {
+            Rule::Keyword(keyword) => lowercase.contains(keyword.as_str()),
+            Rule::Regex(regex) => regex.is_match(text),
+        })
+    }
+}
+
+/// The compiled rules of a `FilterConfig`.
+pub struct Filter {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+}
+
+fn rules(keywords: &[String], regexes: &[String]) -> Result<Vec<Rule>, regex::Error> {
+    let mut rules: Vec<Rule> = keywords
+        .iter()
+        .map(|keyword| Rule::Keyword(keyword.to_lowercase()))
+        .collect();
+    for regex in regexes {
+        let regex = RegexBuilder::new(regex).case_insensitive(true).build()?;
+        rules.push(Rule::Regex(regex));
+    }
+    Ok(rules)
+}
+
+impl Filter {
+    pub fn new(config: &FilterConfig) -> Result<Filter, regex::Error> {
+        Ok(Filter {
+            include: rules(&config.include, &config.include_regex)?,
+            exclude: rules(&config.exclude, &config.exclude_regex)?,
+        })
+    }
+
+    pub fn keeps(&self, item: &Item) -> bool {
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return true;
+        }
+        let mut fields = vec![
+            item.title.as_deref().unwrap_or_default(),
+            item.description.as_deref().unwrap_or_default(),
+            item.author.as_deref().unwrap_or_default(),
+        ];
+        fields.extend(item.categories.iter().map(|c| c.name.as_str()));
+        let fields: Vec<(&str, String)> = fields
+            .into_iter()
+            .map(|field| (field, field.to_lowercase()))
+            .collect();
+
+        let included =
+            self.include.is_empty() || self.include.iter().any(|rule| rule.matches(&fields));
+        included && !self.exclude.iter().any(|rule| rule.matches(&fields))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rss::{Category, ItemBuilder};
+
+    #[test]
+    fn test_filter() {
+        let config = FilterConfig {
+            include: vec!["Rust".to_string()],
+            exclude_regex: vec![r"^\[sponsored\]".to_string()],
+            ..Default::default()
+        };
+        let filter = Filter::new(&config).unwrap();
+        let item = |title: &str| ItemBuilder::default().title(title.to_string()).build();
+
+        assert!(filter.keeps(&item("Announcing rust 1.80")));
+        assert!(!filter.keeps(&item("Announcing Go 1.23")));
+        assert!(!filter.keeps(&item("[Sponsored] Learn Rust fast")));
+
+        let tagged = ItemBuilder::default()
+            .title("Release notes".to_string())
+            .categories(vec![Category {
+                name: "rust".to_string(),
+                domain: None,
+            }])
+            .build();
+        assert!(filter.keeps(&tagged));
+
+        // anchors apply to every field, not only the first one
+        let config = FilterConfig {
+            include_regex: vec!["^rust$".to_string()],
+            exclude_regex: vec![r"notes$".to_string()],
+            ..Default::default()
+        };
+        let filter = Filter::new(&config).unwrap();
+        assert!(!filter.keeps(&tagged));
+        let tagged = ItemBuilder::default()
+            .title("Release notes for 1.80".to_string())
+            .description("Highlights".to_string())
+            .categories(vec![Category {
+                name: "Rust".to_string(),
+                domain: None,
+            }])
+            .build();
+        assert!(filter.keeps(&tagged));
+
+        let config = FilterConfig {
+            include_regex: vec!["(".to_string()],
+            ..Default::default()
+        };
+        assert!(Filter::new(&config).is_err());
+    }
+}
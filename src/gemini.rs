@@ -0,0 +1,145 @@
+use crate::rss_feed::{DailyRss, Rss};
+use handlebars::Handlebars;
+use rss::Channel;
+use serde_json::json;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// A day page, days that fall on the same date share one.
+struct Page<'a> {
+    date: String,
+    day: &'a DailyRss,
+    channels: Vec<&'a Channel>,
+}
+
+impl Page<'_> {
+    fn file(&self) -> String {
+        format!("{}.gmi", self.date)
+    }
+}
+
+fn pages(rss: &Rss) -> Vec<Page<'_>> {
+    let mut pages: Vec<Page> = vec![];
+    for day in &rss.days {
+        let date = day.datetime.format("%Y-%m-%d").to_string();
+        match pages.iter_mut().find(|p| p.date == date) {
+            Some(page) => page.channels.extend(&day.channels),
+            None => pages.push(Page {
+                date,
+                day,
+                channels: day.channels.iter().collect(),
+            }),
+        }
+    }
+    pages
+}
+
+/// Render `index.gmi` and one `%Y-%m-%d.gmi` page per day into `dir`.
+pub fn build_gemini(
+    hbs: &Handlebars<'_>,
+    rss: &Rss,
+    dir: &Path,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let mut generated = vec![];
+    let pages = pages(rss);
+
+    info!("Rendering gemini index!");
+    let index_path = dir.join("index.gmi");
+    let days: Vec<_> = pages
+        .iter()
+        .map(|page| {
+            json!({
+                "date": page.date,
+                "file": page.file(),
+                "datetime": page.day.datetime,
+            })
+        })
+        .collect();
+    let ctx = json!({
+        "site_title": rss.site_title,
+        "project_name": rss.project_name,
+        "project_version": rss.project_version,
+        "project_homepage": rss.project_homepage,
+        "days": days,
+    });
+    let render_result = hbs.render("gemini-index", &ctx)?;
+    File::create(&index_path)?.write_all(render_result.as_bytes())?;
+    generated.push(index_path);
+
+    for page in &pages {
+        let day_path = dir.join(page.file());
+        info!("Rendering gemini page {}!", day_path.to_string_lossy());
+        let ctx = json!({
+            "site_title": rss.site_title,
+            "project_name": rss.project_name,
+            "project_version": rss.project_version,
+            "project_homepage": rss.project_homepage,
+            "date": page.date,
+            "file": page.file(),
+            "datetime": page.day.datetime,
+            "channels": page.channels,
+        });
+        let render_result = hbs.render("gemini-day", &ctx)?;
+        File::create(&day_path)?.write_all(render_result.as_bytes())?;
+        generated.push(day_path);
+    }
+
+    Ok(generated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::render::handlebars;
+    use chrono::{Duration, TimeZone, Utc};
+    use rss::{ChannelBuilder, ItemBuilder};
+
+    #[test]
+    fn test_build_gemini() {
+        let day = |datetime, title: &str| DailyRss {
+            datetime,
+            channels: vec![ChannelBuilder::default()
+                .title(title)
+                // a pub_date of another day must not change the page name
+                .pub_date("Mon, 01 Jan 2018 00:00:00 GMT".to_string())
+                .items(vec![ItemBuilder::default()
+                    .title(format!("{} item", title))
+                    .link("https://example.com/item".to_string())
+                    .build()])
+                .build()],
+        };
+        let morning = Utc.with_ymd_and_hms(2024, 5, 2, 8, 0, 0).unwrap();
+        let rss = Rss {
+            days: vec![
+                day(morning + Duration::hours(10), "Evening"),
+                day(morning, "Morning"),
+                day(morning - Duration::days(1), "Yesterday"),
+            ],
+            ..Default::default()
+        };
+        let hbs = handlebars(&Config::default()).unwrap();
+        let dir = std::env::temp_dir().join("notfeed-gemini");
+        let _ = fs::remove_dir_all(&dir);
+
+        let generated = build_gemini(&hbs, &rss, &dir).unwrap();
+        assert_eq!(generated.len(), 3);
+        let index = fs::read_to_string(dir.join("index.gmi")).unwrap();
+        let links: Vec<&str> = index
+            .lines()
+            .filter_map(|line| line.strip_prefix("=> "))
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|target| target.ends_with(".gmi"))
+            .collect();
+        assert_eq!(links, ["2024-05-02.gmi", "2024-05-01.gmi"]);
+        for link in links {
+            assert!(generated.contains(&dir.join(link)), "{} is missing", link);
+        }
+        let merged = fs::read_to_string(dir.join("2024-05-02.gmi")).unwrap();
+        assert!(merged.contains("## Evening") && merged.contains("## Morning"));
+    }
+}
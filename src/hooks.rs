@@ -0,0 +1,171 @@
+use crate::config::Config;
+use crate::rhai_ext::{describe, engine, timed};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Scope, AST};
+use rss::{Channel, Item};
+use tracing::{info, warn};
+
+/// The Rhai scripts listed in `hooks`, run on every freshly fetched channel.
+///
+/// A script may define any of
+/// - `filter_item(item)` or `filter_item(item, channel)`, returning `false` to drop the item,
+/// - `map_item(item)` or `map_item(item, channel)`, returning the item to keep instead,
+/// - `map_channels(channels)`, returning the channels to keep, in order.
+///
+/// The channel passed to the item hooks comes without its items.
+pub struct Hooks {
+    engine: Engine,
+    scripts: Vec<(String, AST)>,
+}
+
+/// Number of parameters of the script function `name`, if the script defines it.
+fn arity(ast: &AST, name: &str) -> Option<usize> {
+    ast.iter_functions()
+        .find(|f| f.name == name)
+        .map(|f| f.params.len())
+}
+
+impl Hooks {
+    pub fn new(config: &Config) -> Result<Hooks, Box<dyn std::error::Error>> {
+        let engine = engine(&config.rhai);
+        let mut scripts = vec![];
+        for path in &config.hooks {
+            info!("Loading Hook: {}", path);
+            let ast = engine.compile_file(path.into())?;
+            scripts.push((path.clone(), ast));
+        }
+        Ok(Hooks { engine, scripts })
+    }
+
+    fn call<T: Clone + Send + Sync + 'static>(
+        &self,
+        ast: &AST,
+        name: &str,
+        args: Vec<Dynamic>,
+    ) -> Result<T, Box<EvalAltResult>> {
+        timed(|| self.engine.call_fn(&mut Scope::new(), ast, name, args))
+    }
+
+    fn item_args(item: &Item, channel: &Channel, arity: usize) -> Vec<Dynamic> {
+        match arity {
+            1 => vec![Dynamic::from(item.clone())],
+            _ => vec![Dynamic::from(item.clone()), Dynamic::from(channel.clone())],
+        }
+    }
+
+    /// Run every script on `channels`, a failing hook leaves its input as it was.
+    pub fn apply(&self, mut channels: Vec<Channel>) -> Vec<Channel> {
+        for (path, ast) in &self.scripts {
+            let filter = arity(ast, "filter_item");
+            let map = arity(ast, "map_item");
+            if filter.is_some() || map.is_some() {
+                for channel in &mut channels {
+                    let items = std::mem::take(&mut channel.items);
+                    let meta = channel.clone();
+                    channel.items = items
+                        .into_iter()
+                        .filter_map(|item| {
+                            if let Some(arity) = filter {
+                                let args = Hooks::item_args(&item, &meta, arity);
+                                match self.call::<bool>(ast, "filter_item", args) {
+                                    Ok(false) => return None,
+                                    Ok(true) => {}
+                                    Err(err) => warn!("Failed: {} in {}!", describe(&err), path),
+                                }
+                            }
+                            if let Some(arity) = map {
+                                let args = Hooks::item_args(&item, &meta, arity);
+                                match self.call::<Item>(ast, "map_item", args) {
+                                    Ok(mapped) => return Some(mapped),
+                                    Err(err) => warn!("Failed: {} in {}!", describe(&err), path),
+                                }
+                            }
+                            Some(item)
+                        })
+                        .collect();
+                }
+            }
+
+            if arity(ast, "map_channels").is_some() {
+                let array: Array = channels.iter().cloned().map(Dynamic::from).collect();
+                match self.call::<Array>(ast, "map_channels", vec![Dynamic::from(array)]) {
+                    Ok(mapped) => {
+                        channels = mapped
+                            .into_iter()
+                            .filter_map(|c| c.try_cast::<Channel>())
+                            .collect()
+                    }
+                    Err(err) => warn!("Failed: {} in {}!", describe(&err), path),
+                }
+            }
+        }
+        channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RhaiConfig;
+    use rhai::NativeCallContext;
+    use rss::{ChannelBuilder, ItemBuilder};
+
+    #[test]
+    fn test_hooks() {
+        let engine = engine(&Default::default());
+        let script = r#"
+            fn filter_item(item) { !item.title.contains("[ad]") }
+            fn map_item(item, channel) {
+                item.title = channel.title + ": " + item.title;
+                item.add_category("seen");
+                item
+            }
+            fn map_channels(channels) { channels.reverse(); channels }
+        "#;
+        let ast = engine.compile(script).unwrap();
+        let hooks = Hooks {
+            engine,
+            scripts: vec![("test.rhai".to_string(), ast)],
+        };
+
+        let item = |title: &str| ItemBuilder::default().title(title.to_string()).build();
+        let channels = vec![
+            ChannelBuilder::default()
+                .title("arXiv")
+                .items(vec![item("Attention"), item("[ad] Buy now")])
+                .build(),
+            ChannelBuilder::default().title("HN").build(),
+        ];
+        let channels = hooks.apply(channels);
+        assert_eq!(channels[0].title, "HN");
+        assert_eq!(channels[1].items.len(), 1);
+        assert_eq!(
+            channels[1].items[0].title.as_deref(),
+            Some("arXiv: Attention")
+        );
+        assert_eq!(channels[1].items[0].categories[0].name, "seen");
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = RhaiConfig {
+            max_operations: 0,
+            timeout_ms: 50,
+            ..Default::default()
+        };
+        let mut limited = engine(&limits);
+        let err = timed(|| limited.eval::<()>("loop {}")).unwrap_err();
+        assert_eq!(describe(&err), "timed out after 50 ms (line 1, position 6)");
+
+        // scripts run by a script, like helpers called from script tests, keep its deadline
+        limited.register_fn("nested", |ctx: NativeCallContext| {
+            ctx.engine().eval::<i64>("1 + 1")
+        });
+        let err = timed(|| limited.eval::<()>("loop { nested(); }")).unwrap_err();
+        assert!(describe(&err).starts_with("timed out after 50 ms"));
+
+        let err = engine(&Default::default())
+            .eval::<()>("let x = 0;\nloop { x += 1; }")
+            .unwrap_err();
+        assert!(describe(&err).starts_with("Too many operations (line 2"));
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::{MailConfig, MailTls};
+use crate::rss_feed::Rss;
+use chrono::Utc;
+use handlebars::Handlebars;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use tracing::info;
+
+/// Build the context of the digest of the day fetched today, keeping only items not seen on an
+/// earlier day.
+pub fn today_digest(rss: &Rss) -> Option<Value> {
+    let today = Utc::now().date_naive();
+    let (today_days, earlier_days): (Vec<_>, Vec<_>) = rss
+        .days
+        .iter()
+        .partition(|d| d.datetime.date_naive() == today);
+
+    let seen: HashSet<&str> = earlier_days
+        .iter()
+        .flat_map(|d| &d.channels)
+        .flat_map(|c| &c.items)
+        .filter_map(|i| i.link.as_deref())
+        .collect();
+
+    let mut channels = vec![];
+    for day in today_days {
+        for channel in &day.channels {
+            let mut channel = channel.clone();
+            channel
+                .items
+                .retain(|i| !matches!(i.link.as_deref(), Some(l) if seen.contains(l)));
+            if !channel.items.is_empty() {
+                channels.push(channel);
+            }
+        }
+    }
+
+    if channels.is_empty() {
+        return None;
+    }
+
+    Some(json!({
+        "site_title": rss.site_title,
+        "project_name": rss.project_name,
+        "project_version": rss.project_version,
+        "project_homepage": rss.project_homepage,
+        "datetime": Utc::now(),
+        "channels": channels,
+    }))
+}
+
+pub fn build_message(
+    hbs: &Handlebars<'_>,
+    mail: &MailConfig,
+    digest: &Value,
+) -> Result<Message, Box<dyn std::error::Error>> {
+    info!("Rendering mail templates!");
+    let text = hbs.render("mail-text", digest)?;
+    let html = hbs.render("mail-html", digest)?;
+
+    let subject = match &mail.subject {
+        Some(subject) => hbs.render_template(subject, digest)?,
+        None => format!(
+            "{} {}",
+            digest["site_title"].as_str().unwrap_or_default(),
+            Utc::now().format("%Y-%m-%d")
+        ),
+    };
+
+    let mut builder = Message::builder()
+        .from(mail.from.parse::<Mailbox>()?)
+        .subject(subject);
+    for to in &mail.to {
+        builder = builder.to(to.parse::<Mailbox>()?);
+    }
+
+    Ok(builder.multipart(MultiPart::alternative_plain_html(text, html))?)
+}
+
+pub async fn send_message(
+    mail: &MailConfig,
+    message: Message,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let builder = match mail.tls {
+        MailTls::None => {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(mail.smtp_host.as_str())
+        }
+        MailTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&mail.smtp_host)?,
+        MailTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&mail.smtp_host)?,
+    };
+    let builder = match mail.smtp_port {
+        None => builder,
+        Some(port) => builder.port(port),
+    };
+    let builder = match (&mail.username, &mail.password) {
+        (Some(username), Some(password)) => {
+            builder.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => builder,
+    };
+
+    info!("Sending mail via {}!", mail.smtp_host);
+    builder.build().send(message).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::render::handlebars;
+    use crate::rss_feed::DailyRss;
+    use chrono::Duration;
+    use rss::{ChannelBuilder, ItemBuilder};
+
+    #[test]
+    fn test_digest_message() {
+        let item = |title: &str, link: &str| {
+            ItemBuilder::default()
+                .title(title.to_string())
+                .link(link.to_string())
+                .description("<p>An abstract</p>".to_string())
+                .build()
+        };
+        let channel = |items| {
+            ChannelBuilder::default()
+                .title("arXiv cs.CL")
+                // published long before it was fetched
+                .pub_date("Mon, 01 Jan 2018 00:00:00 GMT".to_string())
+                .items(items)
+                .build()
+        };
+        let rss = Rss {
+            site_title: "ArxivDaily".to_string(),
+            days: vec![
+                DailyRss {
+                    datetime: Utc::now(),
+                    channels: vec![channel(vec![
+                        item("Attention", "https://arxiv.org/abs/1706.03762"),
+                        item("BERT", "https://arxiv.org/abs/1810.04805"),
+                    ])],
+                },
+                DailyRss {
+                    datetime: Utc::now() - Duration::days(1),
+                    channels: vec![channel(vec![item(
+                        "BERT",
+                        "https://arxiv.org/abs/1810.04805",
+                    )])],
+                },
+            ],
+            ..Default::default()
+        };
+        let digest = today_digest(&rss).unwrap();
+        let items = digest["channels"][0]["items"].as_array().unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0]["title"], "Attention");
+
+        let mail = MailConfig {
+            smtp_host: "localhost".to_string(),
+            smtp_port: None,
+            tls: MailTls::None,
+            username: None,
+            password: None,
+            password_env: None,
+            password_file: None,
+            subject: Some("{{site_title}} digest".to_string()),
+            from: "NotFeed <feed@example.com>".to_string(),
+            to: vec!["me@example.com".to_string(), "you@example.com".to_string()],
+        };
+        let hbs = handlebars(&Config::default()).unwrap();
+        let message = build_message(&hbs, &mail, &digest).unwrap();
+        let eml = String::from_utf8(message.formatted()).unwrap();
+        assert!(eml.contains("From: NotFeed <feed@example.com>\r\n"));
+        assert!(eml.contains("To: me@example.com, you@example.com\r\n"));
+        assert!(eml.contains("Subject: ArxivDaily digest\r\n"));
+        assert!(eml.contains("MIME-Version: 1.0\r\n"));
+        assert!(eml.contains("Content-Type: multipart/alternative;"));
+        let plain = eml.find("Content-Type: text/plain; charset=utf-8").unwrap();
+        let html = eml.find("Content-Type: text/html; charset=utf-8").unwrap();
+        assert!(plain < html);
+        assert!(eml.contains("https://arxiv.org/abs/1706.03762"));
+    }
+}
@@ -1,24 +1,46 @@
 #[macro_use]
 mod utils;
+mod admin;
+mod api;
+mod compress;
 mod config;
+mod discover;
+mod epub;
+mod extract;
+mod filter;
+mod gemini;
+mod hooks;
+mod mail;
+mod marks;
+mod media;
+mod metrics;
 mod render;
 mod rhai_ext;
 mod rss_feed;
+mod script_tests;
+mod search;
+mod serve;
 
 use crate::utils::copy_statics_to_target;
 use clap::{crate_version, Parser};
 use config::Config;
+use epub::{build_epub, Split};
+use gemini::build_gemini;
+use mail::{build_message, send_message, today_digest};
+use marks::Marks;
 
 use handlebars::no_escape;
 use html_minifier::minify as html_minify;
-use render::handlebars;
+use render::{handlebars, render_markdown};
 use rss_feed::Rss;
+use search::SearchIndex;
+use serve::{serve, ServeOptions, State};
 use std::fs::File;
 use std::io::Write;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::{info, span};
-use warp::{self, Filter};
 
 #[derive(Parser)]
 #[clap(version = crate_version!(), author = "Feng Yunlong <ylfeng@ir.hit.edu.cn>")]
@@ -32,6 +54,12 @@ enum SubCommand {
     Serve(Serve),
     Build(Build),
     Pdf(Pdf),
+    Epub(Epub),
+    Markdown(Markdown),
+    Gemini(Gemini),
+    Mail(Mail),
+    TestScripts(TestScripts),
+    Discover(Discover),
 }
 
 #[derive(Parser)]
@@ -41,6 +69,24 @@ struct Serve {
     addr: String,
     #[clap(short, long, default_value = "8080", help = "port export")]
     port: u16,
+    #[clap(
+        short,
+        long,
+        help = "watch templates, statics, scripts and config and reload"
+    )]
+    watch: bool,
+    #[clap(short, long, help = "refetch feeds every N seconds")]
+    refresh_interval: Option<u64>,
+    #[clap(long, help = "TLS certificate file")]
+    tls_cert: Option<String>,
+    #[clap(long, help = "TLS private key file")]
+    tls_key: Option<String>,
+    #[clap(long, help = "require HTTP basic auth as user:password")]
+    basic_auth: Option<String>,
+    #[clap(long, help = "require this bearer token")]
+    token: Option<String>,
+    #[clap(long, help = "listen on a unix domain socket instead of addr:port")]
+    unix_socket: Option<String>,
 }
 
 #[derive(Parser)]
@@ -57,6 +103,51 @@ struct Pdf {
     output: Option<String>,
 }
 
+#[derive(Parser)]
+#[clap(version = crate_version!(), author = "Feng Yunlong <ylfeng@ir.hit.edu.cn>", about = "Build EPUB.")]
+struct Epub {
+    #[clap(short, long, help = "output filename")]
+    output: Option<String>,
+    #[clap(short, long, value_enum, default_value_t = Split::Day, help = "one chapter per day or per source")]
+    split: Split,
+    #[clap(short, long, help = "bundle inline images")]
+    images: bool,
+}
+
+#[derive(Parser)]
+#[clap(version = crate_version!(), author = "Feng Yunlong <ylfeng@ir.hit.edu.cn>", about = "Build Markdown.")]
+struct Markdown {
+    #[clap(short, long, help = "output filename")]
+    output: Option<String>,
+}
+
+#[derive(Parser)]
+#[clap(version = crate_version!(), author = "Feng Yunlong <ylfeng@ir.hit.edu.cn>", about = "Build Gemini capsule.")]
+struct Gemini {
+    #[clap(short, long, help = "output directory")]
+    output: Option<String>,
+}
+
+#[derive(Parser)]
+#[clap(version = crate_version!(), author = "Feng Yunlong <ylfeng@ir.hit.edu.cn>", about = "Mail today's digest.")]
+struct Mail {
+    #[clap(long, help = "write the .eml to target_dir instead of sending")]
+    dry_run: bool,
+    #[clap(short, long, help = "output filename of --dry-run")]
+    output: Option<String>,
+}
+
+#[derive(Parser)]
+#[clap(version = crate_version!(), author = "Feng Yunlong <ylfeng@ir.hit.edu.cn>", about = "Run the tests of the script helpers.")]
+struct TestScripts {}
+
+#[derive(Parser)]
+#[clap(version = crate_version!(), author = "Feng Yunlong <ylfeng@ir.hit.edu.cn>", about = "List the feeds of a website.")]
+struct Discover {
+    #[clap(help = "website url")]
+    url: String,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let opts: Opts = Opts::parse();
@@ -69,35 +160,66 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _enter = root.enter();
 
     let config = Config::new()?;
+    // these need neither the feeds nor the templates
+    match &opts.subcmd {
+        SubCommand::TestScripts(_) => return script_tests::run(&config),
+        SubCommand::Discover(opt) => {
+            let client = rss_feed::client(&config)?;
+            let found = discover::discover(&client, &opt.url).await?;
+            for (candidate, status) in &found {
+                let status = match status {
+                    Ok(()) => "ok".to_string(),
+                    Err(err) => err.clone(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    candidate.url,
+                    candidate.kind,
+                    candidate.title.as_deref().unwrap_or("-"),
+                    status
+                );
+            }
+            if !found.iter().any(|(_, status)| status.is_ok()) {
+                return Err(format!("no readable feed found for {}", opt.url).into());
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
     let rss = Rss::feed_rss(&config).await?;
     let mut hbs = handlebars(&config)?;
-    let statics_dir = config.statics_dir.as_str();
 
     match opts.subcmd {
         SubCommand::Serve(opt) => {
-            let hbs_ref = Arc::new(hbs);
-
             let socks: SocketAddr = format!("{}:{}", opt.addr, opt.port).parse()?;
-
-            let route = warp::get().and(warp::path::end()).map(move || {
-                let result = hbs_ref
-                    .render("index", &rss)
-                    .unwrap_or_else(|e| e.to_string());
-                warp::reply::html(result)
-            });
-            let static_files = warp::fs::dir(statics_dir.to_string());
-
-            // GET / => rendered index templates
-            // GET /... => statics_dir/...
-            let routes = route.or(static_files);
-
-            warp::serve(routes).run(socks).await;
+            let serve_config = config.serve.clone();
+            let tls = match (
+                opt.tls_cert.or(serve_config.tls_cert),
+                opt.tls_key.or(serve_config.tls_key),
+            ) {
+                (Some(cert), Some(key)) => Some((cert, key)),
+                (None, None) => None,
+                _ => return Err("both tls_cert and tls_key are required for TLS".into()),
+            };
+            let options = ServeOptions {
+                socks,
+                unix_socket: opt.unix_socket.or(serve_config.unix_socket),
+                tls,
+                basic_auth: opt.basic_auth.or(serve_config.basic_auth),
+                token: opt.token.or(serve_config.token),
+                refresh_interval: opt.refresh_interval.map(Duration::from_secs),
+            };
+            let state = Arc::new(State::new(config, hbs, rss, opt.watch));
+            serve(state, options).await?;
         }
         SubCommand::Build(opt) => {
             info!("Copying static files!");
             copy_statics_to_target(&config)?;
             info!("Rendering templates!");
-            let render_result = hbs.render("index", &rss)?;
+            let marks = Marks::load(&config);
+            let mut data = marks.annotate(&rss);
+            media::annotate(&mut data, &rss, &config.sources, None);
+            let render_result = hbs.render("index", &data)?;
             let render_result = if config.minify {
                 info!("Minifying templates!");
                 html_minify(render_result)?
@@ -113,6 +235,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let mut output_file = File::create(&index_path)?;
             output_file.write_all(render_result.as_bytes())?;
             println!("{} generated", index_path.to_string_lossy());
+
+            let render_result = hbs.render("starred", &marks.starred_page(&rss))?;
+            let render_result = if config.minify {
+                html_minify(render_result)?
+            } else {
+                render_result
+            };
+            let starred_path = target_dir.join("starred.html");
+            let mut output_file = File::create(&starred_path)?;
+            output_file.write_all(render_result.as_bytes())?;
+            println!("{} generated", starred_path.to_string_lossy());
+
+            info!("Building search index!");
+            let search_path = target_dir.join("search.json");
+            let search_file = File::create(&search_path)?;
+            serde_json::to_writer(search_file, &SearchIndex::new(&rss))?;
+            println!("{} generated", search_path.to_string_lossy());
         }
         SubCommand::Pdf(opt) => {
             let target_dir = std::path::Path::new(&config.target_dir);
@@ -128,6 +267,57 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             output_file.write_all(render_result.as_bytes())?;
             println!("{} generated", index_path.to_string_lossy());
         }
+        SubCommand::Epub(opt) => {
+            let target_dir = std::path::Path::new(&config.target_dir);
+            let index_path =
+                target_dir.join(opt.output.unwrap_or_else(|| "output.epub".to_string()));
+            let client = if opt.images {
+                Some(rss_feed::client(&config)?)
+            } else {
+                None
+            };
+            info!("Building epub!");
+            build_epub(&hbs, &rss, opt.split, client.as_ref(), &index_path).await?;
+            println!("{} generated", index_path.to_string_lossy());
+        }
+        SubCommand::Markdown(opt) => {
+            let target_dir = std::path::Path::new(&config.target_dir);
+            let index_path = target_dir.join(opt.output.unwrap_or_else(|| "output.md".to_string()));
+            let mut output_file = File::create(&index_path)?;
+            info!("Rendering templates!");
+            let render_result = render_markdown(&hbs, &rss)?;
+            output_file.write_all(render_result.as_bytes())?;
+            println!("{} generated", index_path.to_string_lossy());
+        }
+        SubCommand::Gemini(opt) => {
+            let target_dir = opt.output.unwrap_or_else(|| config.target_dir.clone());
+            for path in build_gemini(&hbs, &rss, std::path::Path::new(&target_dir))? {
+                println!("{} generated", path.to_string_lossy());
+            }
+        }
+        SubCommand::Mail(opt) => {
+            let mail = config.mail.as_ref().ok_or("[mail] is not configured")?;
+            let digest = match today_digest(&rss) {
+                None => {
+                    info!("No new items today!");
+                    return Ok(());
+                }
+                Some(digest) => digest,
+            };
+            let message = build_message(&hbs, mail, &digest)?;
+            if opt.dry_run {
+                let target_dir = std::path::Path::new(&config.target_dir);
+                let index_path =
+                    target_dir.join(opt.output.unwrap_or_else(|| "mail.eml".to_string()));
+                let mut output_file = File::create(&index_path)?;
+                output_file.write_all(&message.formatted())?;
+                println!("{} generated", index_path.to_string_lossy());
+            } else {
+                send_message(mail, message).await?;
+                println!("mail sent to {}", mail.to.join(", "));
+            }
+        }
+        SubCommand::TestScripts(_) | SubCommand::Discover(_) => unreachable!(),
     }
 
     Ok(())
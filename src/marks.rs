@@ -0,0 +1,217 @@
+use crate::config::Config;
+use crate::rss_feed::Rss;
+use crate::utils::fnv1a;
+use chrono::{DateTime, Utc};
+use rss::{Channel, Item};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+pub const MARKS_FILE: &str = "state.json";
+
+/// A starred item, copied so it outlives `cache_max_days`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Starred {
+    pub(crate) id: String,
+    pub(crate) date: DateTime<Utc>,
+    pub(crate) starred_at: DateTime<Utc>,
+    pub(crate) source: String,
+    pub(crate) source_link: String,
+    pub(crate) item: Item,
+}
+
+/// Read and starred state of items, stored as `state.json` next to the cache.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Marks {
+    #[serde(default)]
+    read: BTreeSet<String>,
+    #[serde(default)]
+    starred: Vec<Starred>,
+}
+
+/// Stable ID of an item, from its guid, its link or its source and title, in that order.
+pub fn item_id(channel: &Channel, item: &Item) -> String {
+    let key = match (&item.guid, &item.link) {
+        (Some(guid), _) if !guid.value.is_empty() => guid.value.clone(),
+        (_, Some(link)) if !link.is_empty() => link.clone(),
+        _ => format!(
+            "{}#{}",
+            channel.link,
+            item.title.as_deref().unwrap_or_default()
+        ),
+    };
+    format!("{:016x}", fnv1a(key.as_bytes()))
+}
+
+fn marks_path(config: &Config) -> PathBuf {
+    Path::new(&config.target_dir).join(MARKS_FILE)
+}
+
+impl Marks {
+    pub fn load(config: &Config) -> Marks {
+        let content = match fs::read_to_string(marks_path(config)) {
+            Ok(content) => content,
+            Err(_) => return Default::default(),
+        };
+        match serde_json::from_str(&content) {
+            Ok(marks) => marks,
+            Err(err) => {
+                warn!("Failed: {}!", err.to_string());
+                Default::default()
+            }
+        }
+    }
+
+    pub fn save(&self, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(&config.target_dir)?;
+        let mut f = File::create(marks_path(config))?;
+        serde_json::to_writer(&mut f, self)?;
+        Ok(())
+    }
+
+    pub fn is_read(&self, id: &str) -> bool {
+        self.read.contains(id)
+    }
+
+    pub fn is_starred(&self, id: &str) -> bool {
+        self.starred.iter().any(|s| s.id == id)
+    }
+
+    pub fn set_read(&mut self, id: &str, read: bool) {
+        if read {
+            self.read.insert(id.to_string());
+        } else {
+            self.read.remove(id);
+        }
+    }
+
+    /// Star or unstar an item, starring fails if `rss` has no item `id`.
+    pub fn set_starred(&mut self, rss: &Rss, id: &str, starred: bool) -> bool {
+        if !starred {
+            self.starred.retain(|s| s.id != id);
+            return true;
+        }
+        if self.is_starred(id) {
+            return true;
+        }
+        let found = rss.days.iter().find_map(|day| {
+            day.channels.iter().find_map(|channel| {
+                channel
+                    .items
+                    .iter()
+                    .find(|item| item_id(channel, item) == id)
+                    .map(|item| (day, channel, item))
+            })
+        });
+        match found {
+            None => false,
+            Some((day, channel, item)) => {
+                self.starred.push(Starred {
+                    id: id.to_string(),
+                    date: day.datetime(),
+                    starred_at: Utc::now(),
+                    source: channel.title.clone(),
+                    source_link: channel.link.clone(),
+                    item: item.clone(),
+                });
+                true
+            }
+        }
+    }
+
+    /// Forget read state of items that dropped out of the cache, returns whether any was.
+    pub fn prune(&mut self, rss: &Rss) -> bool {
+        let ids: BTreeSet<String> = rss
+            .days
+            .iter()
+            .flat_map(|day| &day.channels)
+            .flat_map(|channel| channel.items.iter().map(move |item| item_id(channel, item)))
+            .collect();
+        let len = self.read.len();
+        self.read.retain(|id| ids.contains(id));
+        self.read.len() != len
+    }
+
+    fn starred_newest_first(&self) -> Vec<&Starred> {
+        let mut starred: Vec<&Starred> = self.starred.iter().collect();
+        starred.sort_by_key(|s| std::cmp::Reverse(s.starred_at));
+        starred
+    }
+
+    /// `rss` as the templates see it, every item with its `id`, `is_read` and `is_starred`.
+    pub fn annotate(&self, rss: &Rss) -> Value {
+        let mut value = serde_json::to_value(rss).unwrap_or_default();
+        for (d, day) in rss.days.iter().enumerate() {
+            for (c, channel) in day.channels.iter().enumerate() {
+                for (i, item) in channel.items.iter().enumerate() {
+                    let pointer = format!("/days/{}/channels/{}/items/{}", d, c, i);
+                    if let Some(Value::Object(entry)) = value.pointer_mut(&pointer) {
+                        let id = item_id(channel, item);
+                        entry.insert("is_read".to_string(), json!(self.is_read(&id)));
+                        entry.insert("is_starred".to_string(), json!(self.is_starred(&id)));
+                        entry.insert("id".to_string(), json!(id));
+                    }
+                }
+            }
+        }
+        value["starred"] = json!(self.starred_newest_first());
+        value
+    }
+
+    /// What the `starred` template renders.
+    pub fn starred_page(&self, rss: &Rss) -> Value {
+        json!({
+            "site_title": rss.site_title,
+            "project_name": rss.project_name,
+            "project_version": rss.project_version,
+            "project_homepage": rss.project_homepage,
+            "starred": self.starred_newest_first(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rss_feed::DailyRss;
+    use rss::{ChannelBuilder, ItemBuilder};
+
+    #[test]
+    fn test_marks() {
+        let item = ItemBuilder::default()
+            .title("Attention Is All You Need".to_string())
+            .link("https://arxiv.org/abs/1706.03762".to_string())
+            .build();
+        let channel = ChannelBuilder::default().items(vec![item]).build();
+        let id = item_id(&channel, &channel.items[0]);
+        assert_eq!(
+            id,
+            format!("{:016x}", fnv1a(b"https://arxiv.org/abs/1706.03762"))
+        );
+        let mut rss = Rss {
+            days: vec![DailyRss {
+                datetime: Utc::now(),
+                channels: vec![channel],
+            }],
+            ..Default::default()
+        };
+
+        let mut marks = Marks::default();
+        marks.set_read(&id, true);
+        assert!(marks.set_starred(&rss, &id, true));
+        assert!(!marks.set_starred(&rss, "0000000000000000", true));
+        let value = marks.annotate(&rss);
+        let item = &value["days"][0]["channels"][0]["items"][0];
+        assert_eq!(item["id"], id);
+        assert_eq!(item["is_read"], true);
+        assert_eq!(item["is_starred"], true);
+
+        rss.days.clear();
+        assert!(marks.prune(&rss));
+        assert!(!marks.is_read(&id));
+        assert_eq!(marks.starred_page(&rss)["starred"][0]["id"], id);
+    }
+}
@@ -0,0 +1,325 @@
+use crate::config::{MediaMode, Source};
+use crate::rss_feed::{DailyRss, Rss};
+use crate::utils::safe_url;
+use reqwest::Url;
+use rss::extension::Extension;
+use rss::{Channel, Item};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "m4a", "aac", "ogg", "oga", "opus", "wav", "flac"];
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "m4v", "webm", "mov", "ogv"];
+
+/// Where pages rendered by `serve` point proxied media, relative so it works under any prefix
+/// `serve` is mounted at.
+pub const PAGE_ROUTE: &str = "media";
+/// Where the JSON API points proxied media, relative to `/api/...` only an absolute route works.
+pub const API_ROUTE: &str = "/media";
+
+/// The enclosure of an item with what the iTunes and Media RSS extensions say about it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Media {
+    /// Always an absolute http(s) url.
+    pub(crate) url: String,
+    /// What players point at, `url` or the `/media` route when the source is proxied; like
+    /// `image` it is not escaped, templates escape it.
+    pub(crate) src: String,
+    pub(crate) mime_type: Option<String>,
+    /// `"audio"`, `"video"` or `"file"`.
+    pub(crate) kind: String,
+    pub(crate) length: Option<u64>,
+    pub(crate) seconds: Option<u64>,
+    /// `seconds` as `"1:02:03"` or `"2:03"`.
+    pub(crate) duration: Option<String>,
+    pub(crate) image: Option<String>,
+    pub(crate) author: Option<String>,
+    pub(crate) subtitle: Option<String>,
+    pub(crate) summary: Option<String>,
+    pub(crate) episode: Option<String>,
+    pub(crate) season: Option<String>,
+    pub(crate) episode_type: Option<String>,
+    pub(crate) explicit: bool,
+}
+
+/// iTunes durations are seconds, `MM:SS` or `HH:MM:SS`.
+fn parse_duration(text: &str) -> Option<u64> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    let mut seconds: u64 = 0;
+    for part in text.split(':') {
+        // fractions of a second are of no use in a player
+        let part = part.split('.').next()?;
+        let part = part.trim().parse::<u64>().ok()?;
+        seconds = seconds.checked_mul(60)?.checked_add(part)?;
+    }
+    Some(seconds)
+}
+
+fn format_duration(seconds: u64) -> String {
+    let (h, m, s) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    match h {
+        0 => format!("{}:{:02}", m, s),
+        h => format!("{}:{:02}:{:02}", h, m, s),
+    }
+}
+
+fn kind(mime_type: Option<&str>, url: &str) -> String {
+    let mime_type = mime_type.unwrap_or_default().to_lowercase();
+    if mime_type.starts_with("audio/") {
+        return "audio".to_string();
+    }
+    if mime_type.starts_with("video/") {
+        return "video".to_string();
+    }
+    let path = Url::parse(url)
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| url.to_string());
+    let extension = path.rsplit_once('.').map(|(_, e)| e.to_lowercase());
+    match extension.as_deref() {
+        Some(e) if AUDIO_EXTENSIONS.contains(&e) => "audio".to_string(),
+        Some(e) if VIDEO_EXTENSIONS.contains(&e) => "video".to_string(),
+        _ => "file".to_string(),
+    }
+}
+
+/// `media:<name>` of `item`, directly or in a `media:group`.
+fn media_ext<'a>(item: &'a Item, name: &str) -> Option<&'a Extension> {
+    let media = item.extensions.get("media")?;
+    media.get(name).and_then(|e| e.first()).or_else(|| {
+        let group = media.get("group")?.first()?;
+        group.children.get(name)?.first()
+    })
+}
+
+fn non_empty(text: Option<&str>) -> Option<String> {
+    text.map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+}
+
+/// `<route>?url=...`, `route` being `PAGE_ROUTE` or `API_ROUTE`.
+fn proxy_src(route: &str, url: &str) -> String {
+    let mut query = Url::parse("http://localhost/media").unwrap();
+    query.query_pairs_mut().append_pair("url", url);
+    format!("{}?{}", route, query.query().unwrap_or_default())
+}
+
+/// The media of `item`, from its enclosure or else its `media:content`, if that is at an http(s)
+/// url; with a `proxy` route `src` points there instead.
+pub fn media(channel: &Channel, item: &Item, proxy: Option<&str>) -> Option<Media> {
+    let content = media_ext(item, "content");
+    let (url, mime_type, length) = match (&item.enclosure, content) {
+        (Some(enclosure), _) => (
+            enclosure.url.clone(),
+            non_empty(Some(&enclosure.mime_type)),
+            enclosure.length.trim().parse().ok().filter(|l| *l > 0),
+        ),
+        (None, Some(content)) => (
+            content.attrs.get("url")?.clone(),
+            non_empty(content.attrs.get("type").map(String::as_str)),
+            content.attrs.get("fileSize").and_then(|l| l.parse().ok()),
+        ),
+        (None, None) => return None,
+    };
+    let url = safe_url(&url)?;
+
+    let itunes = item.itunes_ext.as_ref();
+    let seconds = itunes
+        .and_then(|i| i.duration.as_deref())
+        .and_then(parse_duration)
+        .or_else(|| parse_duration(content?.attrs.get("duration")?));
+    let thumbnail = media_ext(item, "thumbnail").and_then(|t| t.attrs.get("url"));
+    let image = itunes
+        .and_then(|i| safe_url(i.image.as_deref()?))
+        .or_else(|| safe_url(thumbnail?))
+        .or_else(|| safe_url(channel.itunes_ext.as_ref()?.image.as_deref()?))
+        .or_else(|| safe_url(&channel.image.as_ref()?.url));
+    let explicit = itunes
+        .and_then(|i| i.explicit.as_deref())
+        .is_some_and(|e| matches!(e.trim(), "yes" | "true" | "explicit"));
+
+    Some(Media {
+        src: match proxy {
+            Some(route) => proxy_src(route, &url),
+            None => url.clone(),
+        },
+        kind: kind(mime_type.as_deref(), &url),
+        url,
+        mime_type,
+        length,
+        seconds,
+        duration: seconds.map(format_duration),
+        image,
+        author: non_empty(itunes.and_then(|i| i.author.as_deref())),
+        subtitle: non_empty(itunes.and_then(|i| i.subtitle.as_deref())),
+        summary: non_empty(itunes.and_then(|i| i.summary.as_deref())),
+        episode: non_empty(itunes.and_then(|i| i.episode.as_deref())),
+        season: non_empty(itunes.and_then(|i| i.season.as_deref())),
+        episode_type: non_empty(itunes.and_then(|i| i.episode_type.as_deref())),
+        explicit,
+    })
+}
+
+fn proxied<'a>(sources: &'a [Source]) -> impl Fn(&Channel) -> bool + 'a {
+    move |channel: &Channel| {
+        sources
+            .iter()
+            .any(|s| s.url() == channel.link && s.media() == MediaMode::Proxy)
+    }
+}
+
+/// Give every item of `value`, the serialized `rss`, its `media`; proxied sources only point at
+/// `route` when given, a built site has no `/media` route.
+pub fn annotate(value: &mut Value, rss: &Rss, sources: &[Source], route: Option<&str>) {
+    for (d, day) in rss.days.iter().enumerate() {
+        if let Some(entry) = value.pointer_mut(&format!("/days/{}", d)) {
+            annotate_day(entry, day, sources, route);
+        }
+    }
+}
+
+/// `annotate` for `value`, the serialized `day`.
+pub fn annotate_day(value: &mut Value, day: &DailyRss, sources: &[Source], route: Option<&str>) {
+    let proxied = proxied(sources);
+    for (c, channel) in day.channels.iter().enumerate() {
+        let proxy = route.filter(|_| proxied(channel));
+        for (i, item) in channel.items.iter().enumerate() {
+            let pointer = format!("/channels/{}/items/{}", c, i);
+            if let Some(Value::Object(entry)) = value.pointer_mut(&pointer) {
+                if let Some(media) = media(channel, item, proxy) {
+                    entry.insert("media".to_string(), json!(media));
+                }
+            }
+        }
+    }
+}
+
+/// The media of `item` as the JSON API of `serve` shows it.
+pub fn served(channel: &Channel, item: &Item, sources: &[Source]) -> Option<Media> {
+    let proxy = Some(API_ROUTE).filter(|_| proxied(sources)(channel));
+    media(channel, item, proxy)
+}
+
+/// Whether `url` is the media of an item of a proxied source, the only urls `/media` fetches.
+pub fn is_proxied(rss: &Rss, sources: &[Source], url: &str) -> bool {
+    let proxied = proxied(sources);
+    rss.days
+        .iter()
+        .flat_map(|day| &day.channels)
+        .filter(|channel| proxied(channel))
+        .flat_map(|channel| channel.items.iter().map(move |item| (channel, item)))
+        .any(|(channel, item)| media(channel, item, None).is_some_and(|m| m.url == url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::render::handlebars;
+
+    #[test]
+    fn test_media() {
+        let xml = r#"<?xml version="1.0"?>
+            <rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd"
+                 xmlns:media="http://search.yahoo.com/mrss/">
+            <channel>
+                <title>Talk</title>
+                <link>https://talk.example.com/feed.xml</link>
+                <description>A podcast</description>
+                <itunes:image href="https://talk.example.com/cover.jpg"/>
+                <item>
+                    <title>Episode 1</title>
+                    <enclosure url="https://cdn.example.com/ep1.mp3" length="1024" type="audio/mpeg"/>
+                    <itunes:duration>1:02:03</itunes:duration>
+                    <itunes:episode>1</itunes:episode>
+                    <itunes:explicit>yes</itunes:explicit>
+                </item>
+                <item>
+                    <title>Episode 2</title>
+                    <media:content url="https://cdn.example.com/ep2.webm" duration="95"/>
+                    <media:thumbnail url="https://cdn.example.com/ep2.jpg"/>
+                </item>
+                <item>
+                    <title>Notes</title>
+                </item>
+                <item>
+                    <title>Episode 3</title>
+                    <enclosure url="https://cdn.example.com/ep3.mp3?a=1&amp;b=&quot;&gt;&lt;script&gt;alert(1)&lt;/script&gt;"
+                               type="audio/mpeg"/>
+                    <itunes:image href="javascript:alert(1)"/>
+                </item>
+                <item>
+                    <title>Episode 4</title>
+                    <enclosure url="javascript:alert(1)" type="audio/mpeg"/>
+                </item>
+            </channel>
+            </rss>"#;
+        let channel = Channel::read_from(xml.as_bytes()).unwrap();
+
+        let first = media(&channel, &channel.items[0], None).unwrap();
+        assert_eq!(first.src, "https://cdn.example.com/ep1.mp3");
+        assert_eq!(first.kind, "audio");
+        assert_eq!(first.length, Some(1024));
+        assert_eq!(first.seconds, Some(3723));
+        assert_eq!(first.duration.as_deref(), Some("1:02:03"));
+        assert_eq!(
+            first.image.as_deref(),
+            Some("https://talk.example.com/cover.jpg")
+        );
+        assert_eq!(first.episode.as_deref(), Some("1"));
+        assert!(first.explicit);
+
+        let second = media(&channel, &channel.items[1], Some(PAGE_ROUTE)).unwrap();
+        assert_eq!(second.url, "https://cdn.example.com/ep2.webm");
+        assert_eq!(
+            second.src,
+            "media?url=https%3A%2F%2Fcdn.example.com%2Fep2.webm"
+        );
+        assert_eq!(second.kind, "video");
+        assert_eq!(second.duration.as_deref(), Some("1:35"));
+        assert_eq!(
+            second.image.as_deref(),
+            Some("https://cdn.example.com/ep2.jpg")
+        );
+
+        assert!(media(&channel, &channel.items[2], None).is_none());
+
+        // urls stay as they are for JSON, templates escape them
+        let third = media(&channel, &channel.items[3], None).unwrap();
+        assert_eq!(
+            third.src,
+            "https://cdn.example.com/ep3.mp3?a=1&b=%22%3E%3Cscript%3Ealert(1)%3C/script%3E"
+        );
+        assert_eq!(
+            third.image.as_deref(),
+            Some("https://talk.example.com/cover.jpg")
+        );
+        assert!(media(&channel, &channel.items[4], None).is_none());
+
+        let rss = Rss {
+            days: vec![DailyRss {
+                datetime: chrono::Utc::now(),
+                channels: vec![Channel {
+                    items: vec![channel.items[3].clone()],
+                    ..channel.clone()
+                }],
+            }],
+            ..Default::default()
+        };
+        let mut data = serde_json::to_value(&rss).unwrap();
+        annotate(&mut data, &rss, &[], None);
+        let html = handlebars(&Config::default())
+            .unwrap()
+            .render("index", &data)
+            .unwrap();
+        assert!(html.contains("ep3.mp3?a=1&amp;b=%22%3E%3Cscript%3Ealert(1)%3C&#x2F;script%3E"));
+        assert!(!html.contains("<script>alert"));
+        assert_eq!(parse_duration("5400"), Some(5400));
+        assert_eq!(parse_duration("12:34.5"), Some(754));
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration("18446744073709551615:59"), None);
+        assert_eq!(parse_duration("99999999999999999:0:0"), None);
+    }
+}
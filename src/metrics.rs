@@ -0,0 +1,276 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+lazy_static! {
+    /// Process wide counters, exported by `serve` at `/metrics`.
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Debug, Default)]
+struct SourceStats {
+    fetches: u64,
+    failures: u64,
+    fetch_seconds: f64,
+    last_fetch_seconds: f64,
+    items: usize,
+    filtered: usize,
+    last_success: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+struct Summary {
+    count: u64,
+    seconds: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sources: Mutex<BTreeMap<String, SourceStats>>,
+    renders: Mutex<BTreeMap<String, Summary>>,
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    refreshed: Mutex<Option<DateTime<Utc>>>,
+    refresh_failures: AtomicU64,
+    refresh_failing: AtomicBool,
+}
+
+impl Metrics {
+    /// Record one fetch of `source`, `items` is `None` when it failed.
+    pub fn record_fetch(&self, source: &str, elapsed: Duration, items: Option<usize>) {
+        let mut sources = self.sources.lock().unwrap();
+        let stats = sources.entry(source.to_string()).or_default();
+        stats.fetches += 1;
+        stats.fetch_seconds += elapsed.as_secs_f64();
+        stats.last_fetch_seconds = elapsed.as_secs_f64();
+        match items {
+            Some(items) => {
+                stats.items = items;
+                stats.last_success = Some(Utc::now());
+            }
+            None => stats.failures += 1,
+        }
+    }
+
+    /// Record how many items of the latest fetch of `source` the filters dropped.
+    pub fn record_filtered(&self, source: &str, filtered: usize) {
+        let mut sources = self.sources.lock().unwrap();
+        sources.entry(source.to_string()).or_default().filtered = filtered;
+    }
+
+    pub fn record_render(&self, template: &str, elapsed: Duration) {
+        let mut renders = self.renders.lock().unwrap();
+        let summary = renders.entry(template.to_string()).or_default();
+        summary.count += 1;
+        summary.seconds += elapsed.as_secs_f64();
+    }
+
+    pub fn record_request(&self, method: &str, status: u16) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests.entry((method.to_string(), status)).or_default() += 1;
+    }
+
+    pub fn record_refresh(&self) {
+        *self.refreshed.lock().unwrap() = Some(Utc::now());
+        self.refresh_failing.store(false, Ordering::Relaxed);
+    }
+
+    pub fn record_refresh_failure(&self) {
+        self.refresh_failures.fetch_add(1, Ordering::Relaxed);
+        self.refresh_failing.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the latest refresh of the feeds failed.
+    pub fn refresh_failing(&self) -> bool {
+        self.refresh_failing.load(Ordering::Relaxed)
+    }
+
+    /// Everything recorded so far, in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        type SourceValue = fn(&SourceStats) -> Option<f64>;
+        let per_source: [(&str, &str, &str, SourceValue); 7] = [
+            (
+                "notfeed_source_fetches_total",
+                "counter",
+                "Fetches per source.",
+                |s| Some(s.fetches as f64),
+            ),
+            (
+                "notfeed_source_fetch_failures_total",
+                "counter",
+                "Failed fetches per source.",
+                |s| Some(s.failures as f64),
+            ),
+            (
+                "notfeed_source_fetch_duration_seconds_total",
+                "counter",
+                "Time spent fetching per source.",
+                |s| Some(s.fetch_seconds),
+            ),
+            (
+                "notfeed_source_last_fetch_duration_seconds",
+                "gauge",
+                "Latency of the latest fetch per source.",
+                |s| Some(s.last_fetch_seconds),
+            ),
+            (
+                "notfeed_source_items",
+                "gauge",
+                "Items in the latest successful fetch per source.",
+                |s| Some(s.items as f64),
+            ),
+            (
+                "notfeed_source_filtered_items",
+                "gauge",
+                "Items the filters dropped from the latest fetch per source.",
+                |s| Some(s.filtered as f64),
+            ),
+            (
+                "notfeed_source_last_success_timestamp_seconds",
+                "gauge",
+                "Unix time of the latest successful fetch per source.",
+                |s| s.last_success.map(|t| t.timestamp() as f64),
+            ),
+        ];
+        let sources = self.sources.lock().unwrap();
+        for (name, kind, help, value) in per_source {
+            header(&mut out, name, kind, help);
+            for (source, stats) in sources.iter() {
+                if let Some(value) = value(stats) {
+                    sample(&mut out, name, &[("source", source)], value);
+                }
+            }
+        }
+        drop(sources);
+
+        header(
+            &mut out,
+            "notfeed_last_refresh_timestamp_seconds",
+            "gauge",
+            "Unix time of the latest successful refresh.",
+        );
+        if let Some(refreshed) = *self.refreshed.lock().unwrap() {
+            sample(
+                &mut out,
+                "notfeed_last_refresh_timestamp_seconds",
+                &[],
+                refreshed.timestamp() as f64,
+            );
+        }
+        header(
+            &mut out,
+            "notfeed_refresh_failures_total",
+            "counter",
+            "Failed refreshes.",
+        );
+        sample(
+            &mut out,
+            "notfeed_refresh_failures_total",
+            &[],
+            self.refresh_failures.load(Ordering::Relaxed) as f64,
+        );
+
+        let renders = self.renders.lock().unwrap();
+        header(
+            &mut out,
+            "notfeed_render_duration_seconds",
+            "summary",
+            "Time spent rendering templates.",
+        );
+        for (template, summary) in renders.iter() {
+            sample(
+                &mut out,
+                "notfeed_render_duration_seconds_sum",
+                &[("template", template)],
+                summary.seconds,
+            );
+            sample(
+                &mut out,
+                "notfeed_render_duration_seconds_count",
+                &[("template", template)],
+                summary.count as f64,
+            );
+        }
+        drop(renders);
+
+        let requests = self.requests.lock().unwrap();
+        header(
+            &mut out,
+            "notfeed_http_requests_total",
+            "counter",
+            "HTTP requests served.",
+        );
+        for ((method, status), count) in requests.iter() {
+            let status = status.to_string();
+            sample(
+                &mut out,
+                "notfeed_http_requests_total",
+                &[("method", method), ("status", &status)],
+                *count as f64,
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: f64) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+        .collect();
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record_fetch(
+            "https://example.com/\"feed\"",
+            Duration::from_millis(500),
+            Some(3),
+        );
+        metrics.record_fetch(
+            "https://example.com/\"feed\"",
+            Duration::from_millis(500),
+            None,
+        );
+        metrics.record_request("GET", 200);
+        let text = metrics.render();
+        assert!(text.contains(
+            "notfeed_source_fetches_total{source=\"https://example.com/\\\"feed\\\"\"} 2\n"
+        ));
+        assert!(text.contains(
+            "notfeed_source_fetch_failures_total{source=\"https://example.com/\\\"feed\\\"\"} 1\n"
+        ));
+        assert!(
+            text.contains("notfeed_source_items{source=\"https://example.com/\\\"feed\\\"\"} 3\n")
+        );
+        assert!(text.contains("notfeed_http_requests_total{method=\"GET\",status=\"200\"} 1\n"));
+    }
+}
@@ -1,13 +1,22 @@
-use crate::rhai_ext::{PlusPackage, RhaiMatch};
-use crate::utils::{command_escape, remove_unpair, PDF_SRC, TEMPLATES_SRC};
+use crate::rhai_ext::{describe, engine, timed};
+use crate::rss_feed::Rss;
+use crate::utils::{
+    command_escape, remove_unpair, sanitize_html, strip_tags, to_xhtml, EPUB_SRC, GEMINI_DAY_SRC,
+    GEMINI_INDEX_SRC, MAIL_HTML_SRC, MAIL_TEXT_SRC, MARKDOWN_SRC, PDF_SRC, SEARCH_SRC, STARRED_SRC,
+    TEMPLATES_SRC,
+};
 use crate::Config;
 use chrono::{DateTime, SecondsFormat, Utc};
-use handlebars::Handlebars;
 use handlebars::{no_escape, Context, Helper, Output, RenderContext, RenderError};
-use html_escape::decode_html_entities;
+use handlebars::{Handlebars, HelperDef, JsonValue, ScopedJson};
+use html2md::parse_html;
+use html_escape::{decode_html_entities, encode_safe};
 use latex2mathml::replace;
-use regex::Regex;
-use rhai::packages::Package;
+use rhai::serde::{from_dynamic, to_dynamic};
+use rhai::{Dynamic, Engine, Scope, AST};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
 use tracing::info;
 
 #[cfg(feature = "handlebars_misc_helpers")]
@@ -15,15 +24,9 @@ use handlebars_misc_helpers::setup_handlebars;
 
 pub fn handlebars(config: &Config) -> Result<Handlebars<'static>, Box<dyn std::error::Error>> {
     info!("Building Script Engine!");
-    let mut engine = rhai::Engine::new();
-    engine
-        .register_type_with_name::<Regex>("Regex")
-        .register_type_with_name::<RhaiMatch>("Match");
-
+    let engine = engine(&config.rhai);
     info!("Building Script Engine Done!");
 
-    engine.register_global_module(PlusPackage::new().as_shared_module());
-
     info!("Building Handlebars Render Engine!");
     let mut handlebars = Handlebars::new();
 
@@ -33,17 +36,37 @@ pub fn handlebars(config: &Config) -> Result<Handlebars<'static>, Box<dyn std::e
     handlebars.register_helper("time_format", Box::new(time_format_helper));
     handlebars.register_helper("latex_render", Box::new(latex_render_helper));
     handlebars.register_helper("latex_escape", Box::new(latex_escape_helper));
+    handlebars.register_helper("xhtml", Box::new(xhtml_helper));
+    handlebars.register_helper("markdown", Box::new(markdown_helper));
+    handlebars.register_helper("plain_text", Box::new(plain_text_helper));
+    handlebars.register_helper("sanitize", Box::new(sanitize_helper));
+    handlebars.register_helper("escape", Box::new(escape_helper));
     #[cfg(feature = "handlebars_misc_helpers")]
     setup_handlebars(&mut handlebars);
 
     handlebars.register_escape_fn(no_escape);
     handlebars.register_template_string("pdf", PDF_SRC)?;
+    handlebars.register_template_string("epub", EPUB_SRC)?;
+    handlebars.register_template_string("markdown", MARKDOWN_SRC)?;
+    handlebars.register_template_string("gemini-index", GEMINI_INDEX_SRC)?;
+    handlebars.register_template_string("gemini-day", GEMINI_DAY_SRC)?;
+    handlebars.register_template_string("mail-text", MAIL_TEXT_SRC)?;
+    handlebars.register_template_string("mail-html", MAIL_HTML_SRC)?;
+    handlebars.register_template_string("search", SEARCH_SRC)?;
+    handlebars.register_template_string("starred", STARRED_SRC)?;
     handlebars.register_template_string("index", TEMPLATES_SRC)?;
     handlebars.register_templates_directory(".hbs", &config.templates_dir)?;
 
+    let public_config = public_config(config)?;
     for (name, script_path) in &config.scripts {
         info!("Loading Script: {} => {}", name, script_path);
-        handlebars.register_script_helper_file(name, script_path)?;
+        let helper = ScriptHelper::new(
+            handlebars.engine(),
+            name,
+            script_path,
+            public_config.clone(),
+        )?;
+        handlebars.register_helper(name, Box::new(helper));
     }
 
     info!("Building Handlebars Render Engine Done!");
@@ -51,6 +74,136 @@ pub fn handlebars(config: &Config) -> Result<Handlebars<'static>, Box<dyn std::e
     Ok(handlebars)
 }
 
+/// Render the `markdown` template, the days of the latest date merged so `@first` is all of it.
+pub fn render_markdown(hbs: &Handlebars<'_>, rss: &Rss) -> Result<String, RenderError> {
+    let mut data = serde_json::to_value(rss)?;
+    data["days"] = serde_json::to_value(rss.merged_days())?;
+    hbs.render("markdown", &data)
+}
+
+/// A Rhai script as a helper, like `register_script_helper_file` but errors name the script.
+///
+/// Besides `params` and `hash` the script sees the constants `config`, the config without
+/// credentials, and `root`, the whole render context; converting the context for every call is
+/// not cheap, so `root` is only set for scripts that mention it.
+struct ScriptHelper {
+    name: String,
+    path: String,
+    ast: AST,
+    uses_root: bool,
+    config: Dynamic,
+}
+
+/// Config values safe to hand to scripts.
+pub(crate) fn public_config(config: &Config) -> Result<Dynamic, Box<dyn std::error::Error>> {
+    let mut value = serde_json::to_value(config)?;
+    if let Some(map) = value.as_object_mut() {
+        for secret in [
+            "admin_token",
+            "admin_token_env",
+            "admin_token_file",
+            "proxy",
+            "mail",
+            "serve",
+        ] {
+            map.remove(secret);
+        }
+    }
+    Ok(to_dynamic(value)?.into_shared())
+}
+
+impl ScriptHelper {
+    fn new(
+        engine: &Engine,
+        name: &str,
+        path: &str,
+        config: Dynamic,
+    ) -> Result<ScriptHelper, RenderError> {
+        let (ast, uses_root) = ScriptHelper::compile(engine, name, path)?;
+        Ok(ScriptHelper {
+            name: name.to_string(),
+            path: path.to_string(),
+            ast,
+            uses_root,
+            config,
+        })
+    }
+
+    fn compile(engine: &Engine, name: &str, path: &str) -> Result<(AST, bool), RenderError> {
+        let failed =
+            |err: String| RenderError::new(format!("Script helper {} ({}): {}", name, path, err));
+        let script = fs::read_to_string(path).map_err(|err| failed(err.to_string()))?;
+        let mut ast = engine
+            .compile(&script)
+            .map_err(|err| failed(err.to_string()))?;
+        ast.set_source(path);
+        Ok((ast, script.contains("root")))
+    }
+}
+
+/// What a script helper sees, also used by `test-scripts`.
+pub(crate) fn helper_scope(
+    params: Dynamic,
+    hash: Dynamic,
+    config: Dynamic,
+    root: Option<Dynamic>,
+) -> Scope<'static> {
+    let mut scope = Scope::new();
+    scope.push_dynamic("params", params);
+    scope.push_dynamic("hash", hash);
+    scope.push_constant_dynamic("config", config);
+    if let Some(root) = root {
+        scope.push_constant_dynamic("root", root);
+    }
+    scope
+}
+
+impl HelperDef for ScriptHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let engine = r.engine();
+        // the script is picked up again on every call in dev mode, like handlebars does
+        let (ast, uses_root) = if r.dev_mode() {
+            let (ast, uses_root) = ScriptHelper::compile(engine, &self.name, &self.path)?;
+            (Cow::Owned(ast), uses_root)
+        } else {
+            (Cow::Borrowed(&self.ast), self.uses_root)
+        };
+        let failed = |err: &rhai::EvalAltResult| {
+            RenderError::new(format!(
+                "Script helper {} ({}): {}",
+                self.name,
+                self.path,
+                describe(err)
+            ))
+        };
+
+        let params: Vec<&JsonValue> = h.params().iter().map(|p| p.value()).collect();
+        let hash: HashMap<&str, &JsonValue> =
+            h.hash().iter().map(|(k, v)| (*k, v.value())).collect();
+        let root = match uses_root {
+            true => Some(to_dynamic(ctx.data()).map_err(|err| failed(&err))?),
+            false => None,
+        };
+        let mut scope = helper_scope(
+            to_dynamic(params).map_err(|err| failed(&err))?,
+            to_dynamic(hash).map_err(|err| failed(&err))?,
+            self.config.clone(),
+            root,
+        );
+
+        let result = timed(|| engine.eval_ast_with_scope::<Dynamic>(&mut scope, &ast))
+            .map_err(|err| failed(&err))?;
+        let result: JsonValue = from_dynamic(&result).map_err(|err| failed(&err))?;
+        Ok(ScopedJson::Derived(result))
+    }
+}
+
 fn time_format_helper(
     h: &Helper,
     _: &Handlebars,
@@ -123,6 +276,68 @@ fn latex_escape_helper(
     Ok(())
 }
 
+fn xhtml_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    // missing fields (e.g. items without description) render as empty
+    let text = h.param(0).and_then(|v| v.value().as_str()).unwrap_or("");
+    out.write(&to_xhtml(text))?;
+    Ok(())
+}
+
+fn markdown_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    let text = h.param(0).and_then(|v| v.value().as_str()).unwrap_or("");
+    out.write(parse_html(text).trim())?;
+    Ok(())
+}
+
+fn sanitize_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    let text = h.param(0).and_then(|v| v.value().as_str()).unwrap_or("");
+    out.write(&sanitize_html(text))?;
+    Ok(())
+}
+
+/// Escape plain text, like a title, for HTML text and quoted attributes.
+fn escape_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    let text = h.param(0).and_then(|v| v.value().as_str()).unwrap_or("");
+    out.write(&encode_safe(text))?;
+    Ok(())
+}
+
+fn plain_text_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> Result<(), RenderError> {
+    let text = h.param(0).and_then(|v| v.value().as_str()).unwrap_or("");
+    out.write(&strip_tags(text))?;
+    Ok(())
+}
+
 fn latex_render_helper(
     h: &Helper,
     _: &Handlebars,
@@ -193,3 +408,54 @@ fn build_time_helper(
     out.write(&rendered)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rss_feed::DailyRss;
+    use chrono::{Duration, TimeZone};
+    use rss::{ChannelBuilder, ItemBuilder};
+
+    #[test]
+    fn test_escape() {
+        let hbs = handlebars(&Config::default()).unwrap();
+        let data = serde_json::json!({ "title": r#"<b>"Tom" & 'Jerry'</b>"# });
+        assert_eq!(
+            hbs.render_template("{{escape title}}", &data).unwrap(),
+            "&lt;b&gt;&quot;Tom&quot; &amp; &#x27;Jerry&#x27;&lt;&#x2F;b&gt;"
+        );
+    }
+
+    #[test]
+    fn test_markdown() {
+        let day = |hours: i64, title: &str| DailyRss {
+            datetime: Utc.with_ymd_and_hms(2024, 5, 2, 20, 0, 0).unwrap() - Duration::hours(hours),
+            channels: vec![ChannelBuilder::default()
+                .title("arXiv cs.CL")
+                .items(vec![ItemBuilder::default()
+                    .title(title.to_string())
+                    .link("https://arxiv.org/abs/1706.03762".to_string())
+                    .description("<p>An <b>abstract</b></p>".to_string())
+                    .build()])
+                .build()],
+        };
+        let rss = Rss {
+            site_title: "ArxivDaily".to_string(),
+            days: vec![
+                day(0, "Attention"),
+                day(10, "Morning"),
+                day(24, "Yesterday"),
+            ],
+            ..Default::default()
+        };
+        let hbs = handlebars(&Config::default()).unwrap();
+        let markdown = render_markdown(&hbs, &rss).unwrap();
+        assert!(markdown.starts_with("# ArxivDaily\n"));
+        assert_eq!(markdown.matches("## 2024-05-02\n").count(), 1);
+        assert!(markdown.contains("### arXiv cs.CL\n"));
+        assert!(markdown.contains("#### [Attention](https://arxiv.org/abs/1706.03762)\n"));
+        assert!(markdown.contains("An **abstract**"));
+        assert!(markdown.contains("[Morning]"));
+        assert!(!markdown.contains("Yesterday"));
+    }
+}
@@ -1,13 +1,97 @@
 #![allow(non_snake_case)]
 
+use crate::config::RhaiConfig;
+use regex::Regex;
+use rhai::packages::Package;
 use rhai::plugin::*;
 use rhai::{def_package, packages::StandardPackage};
+use rhai::{Array, Map, FLOAT};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
 
 def_package! {
     pub PlusPackage(module) {
         StandardPackage::init(module);
         combine_with_exported_module!(module, "regex", regex_module);
         combine_with_exported_module!(module, "utils", utils_module);
+        combine_with_exported_module!(module, "feed", feed_module);
+        combine_with_exported_module!(module, "datetime", datetime_module);
+        combine_with_exported_module!(module, "html", html_module);
+        combine_with_exported_module!(module, "json", json_module);
+        combine_with_exported_module!(module, "collections", collections_module);
+    }
+}
+
+thread_local! {
+    /// When the outermost script running on this thread started, scripts that call helpers run
+    /// nested scripts on the same thread and those share its deadline.
+    static STARTED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Clears `STARTED` when the outermost run ends, even by a panic.
+struct Outermost;
+
+impl Drop for Outermost {
+    fn drop(&mut self) {
+        STARTED.with(|started| started.set(None));
+    }
+}
+
+/// Run scripts under the `timeout_ms` of their engine, counted from the outermost `timed` on this
+/// thread; scripts run outside of it only have the other limits.
+pub fn timed<T>(run: impl FnOnce() -> T) -> T {
+    let _outermost = STARTED.with(|started| match started.get() {
+        Some(_) => None,
+        None => {
+            started.set(Some(Instant::now()));
+            Some(Outermost)
+        }
+    });
+    run()
+}
+
+/// The engine both the Handlebars script helpers and the feed hooks run on.
+pub fn engine(limits: &RhaiConfig) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .register_type_with_name::<Regex>("Regex")
+        .register_type_with_name::<RhaiMatch>("Match")
+        .register_type_with_name::<chrono::DateTime<chrono::FixedOffset>>("DateTime")
+        .register_type_with_name::<chrono::Duration>("Duration");
+    engine.register_global_module(PlusPackage::new().as_shared_module());
+
+    engine
+        .set_max_operations(limits.max_operations)
+        .set_max_call_levels(match limits.max_call_levels {
+            0 => usize::MAX,
+            levels => levels,
+        })
+        .set_max_string_size(limits.max_string_size)
+        .set_max_array_size(limits.max_array_size)
+        .set_max_map_size(limits.max_map_size);
+
+    if limits.timeout_ms > 0 {
+        let timeout_ms = limits.timeout_ms;
+        let timeout = Duration::from_millis(timeout_ms);
+        engine.on_progress(move |ops| {
+            let elapsed = STARTED.with(|started| started.get().map(|started| started.elapsed()));
+            if ops % 1024 == 0 && elapsed.is_some_and(|elapsed| elapsed > timeout) {
+                Some(format!("timed out after {} ms", timeout_ms).into())
+            } else {
+                None
+            }
+        });
+    }
+    engine
+}
+
+/// `err` with where it happened, unlike `Display` this keeps why the script was terminated.
+pub fn describe(err: &EvalAltResult) -> String {
+    match err {
+        EvalAltResult::ErrorTerminated(reason, pos) if pos.is_none() => reason.to_string(),
+        EvalAltResult::ErrorTerminated(reason, pos) => format!("{} ({})", reason, pos),
+        err => err.to_string(),
     }
 }
 
@@ -18,6 +102,41 @@ pub struct RhaiMatch {
     pub(crate) end: usize,
 }
 
+/// Numbers by value, strings and everything else by their text, missing values last.
+fn compare_values(a: &Dynamic, b: &Dynamic) -> Ordering {
+    let number = |v: &Dynamic| {
+        v.as_int()
+            .map(|i| i as FLOAT)
+            .or_else(|_| v.as_float())
+            .ok()
+    };
+    match (a.is_unit(), b.is_unit()) {
+        (true, true) => return Ordering::Equal,
+        (true, false) => return Ordering::Greater,
+        (false, true) => return Ordering::Less,
+        _ => {}
+    }
+    match (number(a), number(b)) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+fn property(item: &Dynamic, key: &str) -> Dynamic {
+    item.read_lock::<Map>()
+        .and_then(|map| map.get(key).cloned())
+        .unwrap_or(Dynamic::UNIT)
+}
+
+fn push_grouped(groups: &mut Map, key: String, item: Dynamic) {
+    let group = groups
+        .entry(key.into())
+        .or_insert_with(|| Dynamic::from_array(Array::new()));
+    if let Some(mut group) = group.write_lock::<Array>() {
+        group.push(item);
+    }
+}
+
 #[export_module]
 mod utils_module {
     use html_escape::decode_html_entities;
@@ -34,6 +153,669 @@ mod utils_module {
     }
 }
 
+#[export_module]
+mod feed_module {
+    use rhai::{Array, Dynamic, ImmutableString};
+    use rss::{Category, Guid};
+
+    pub type Item = rss::Item;
+    pub type Channel = rss::Channel;
+
+    #[rhai_fn(get = "title")]
+    pub fn item_get_title(item: &mut Item) -> ImmutableString {
+        item.title.clone().unwrap_or_default().into()
+    }
+
+    #[rhai_fn(set = "title")]
+    pub fn item_set_title(item: &mut Item, title: ImmutableString) {
+        item.title = Some(title.to_string());
+    }
+
+    #[rhai_fn(get = "link")]
+    pub fn item_get_link(item: &mut Item) -> ImmutableString {
+        item.link.clone().unwrap_or_default().into()
+    }
+
+    #[rhai_fn(set = "link")]
+    pub fn item_set_link(item: &mut Item, link: ImmutableString) {
+        item.link = Some(link.to_string());
+    }
+
+    #[rhai_fn(get = "description")]
+    pub fn item_get_description(item: &mut Item) -> ImmutableString {
+        item.description.clone().unwrap_or_default().into()
+    }
+
+    #[rhai_fn(set = "description")]
+    pub fn item_set_description(item: &mut Item, description: ImmutableString) {
+        item.description = Some(description.to_string());
+    }
+
+    #[rhai_fn(get = "content")]
+    pub fn item_get_content(item: &mut Item) -> ImmutableString {
+        item.content.clone().unwrap_or_default().into()
+    }
+
+    #[rhai_fn(set = "content")]
+    pub fn item_set_content(item: &mut Item, content: ImmutableString) {
+        item.content = Some(content.to_string());
+    }
+
+    #[rhai_fn(get = "author")]
+    pub fn item_get_author(item: &mut Item) -> ImmutableString {
+        item.author.clone().unwrap_or_default().into()
+    }
+
+    #[rhai_fn(set = "author")]
+    pub fn item_set_author(item: &mut Item, author: ImmutableString) {
+        item.author = Some(author.to_string());
+    }
+
+    #[rhai_fn(get = "pub_date")]
+    pub fn item_get_pub_date(item: &mut Item) -> ImmutableString {
+        item.pub_date.clone().unwrap_or_default().into()
+    }
+
+    #[rhai_fn(set = "pub_date")]
+    pub fn item_set_pub_date(item: &mut Item, pub_date: ImmutableString) {
+        item.pub_date = Some(pub_date.to_string());
+    }
+
+    #[rhai_fn(get = "guid")]
+    pub fn item_get_guid(item: &mut Item) -> ImmutableString {
+        item.guid.as_ref().map(|g| g.value.clone()).unwrap_or_default().into()
+    }
+
+    #[rhai_fn(set = "guid")]
+    pub fn item_set_guid(item: &mut Item, guid: ImmutableString) {
+        item.guid = Some(Guid {
+            value: guid.to_string(),
+            permalink: false,
+        });
+    }
+
+    #[rhai_fn(get = "categories")]
+    pub fn item_get_categories(item: &mut Item) -> Array {
+        item.categories
+            .iter()
+            .map(|c| Dynamic::from(c.name.clone()))
+            .collect()
+    }
+
+    #[rhai_fn(set = "categories")]
+    pub fn item_set_categories(item: &mut Item, categories: Array) {
+        item.categories = categories
+            .into_iter()
+            .map(|c| Category {
+                name: c.to_string(),
+                domain: None,
+            })
+            .collect();
+    }
+
+    #[rhai_fn(name = "add_category")]
+    pub fn item_add_category(item: &mut Item, name: &str) {
+        if !item.categories.iter().any(|c| c.name == name) {
+            item.categories.push(Category {
+                name: name.to_string(),
+                domain: None,
+            });
+        }
+    }
+
+    #[rhai_fn(get = "title")]
+    pub fn channel_get_title(channel: &mut Channel) -> ImmutableString {
+        channel.title.as_str().into()
+    }
+
+    #[rhai_fn(set = "title")]
+    pub fn channel_set_title(channel: &mut Channel, title: ImmutableString) {
+        channel.title = title.to_string();
+    }
+
+    #[rhai_fn(get = "link")]
+    pub fn channel_get_link(channel: &mut Channel) -> ImmutableString {
+        channel.link.as_str().into()
+    }
+
+    #[rhai_fn(set = "link")]
+    pub fn channel_set_link(channel: &mut Channel, link: ImmutableString) {
+        channel.link = link.to_string();
+    }
+
+    #[rhai_fn(get = "description")]
+    pub fn channel_get_description(channel: &mut Channel) -> ImmutableString {
+        channel.description.as_str().into()
+    }
+
+    #[rhai_fn(set = "description")]
+    pub fn channel_set_description(channel: &mut Channel, description: ImmutableString) {
+        channel.description = description.to_string();
+    }
+
+    #[rhai_fn(get = "items")]
+    pub fn channel_get_items(channel: &mut Channel) -> Array {
+        channel.items.iter().cloned().map(Dynamic::from).collect()
+    }
+
+    #[rhai_fn(set = "items")]
+    pub fn channel_set_items(channel: &mut Channel, items: Array) {
+        channel.items = items
+            .into_iter()
+            .filter_map(|item| item.try_cast::<Item>())
+            .collect();
+    }
+}
+
+#[export_module]
+mod datetime_module {
+    use chrono::{FixedOffset, Local, NaiveDateTime, Utc};
+    use rhai::{EvalAltResult, ImmutableString, Position, INT};
+
+    pub type DateTime = chrono::DateTime<FixedOffset>;
+    pub type Duration = chrono::Duration;
+
+    fn failed(name: &str, err: impl ToString) -> Box<EvalAltResult> {
+        EvalAltResult::ErrorInFunctionCall(
+            name.to_string(),
+            err.to_string(),
+            "".into(),
+            Position::NONE,
+        )
+            .into()
+    }
+
+    #[rhai_fn(name = "now")]
+    pub fn now() -> DateTime {
+        Utc::now().fixed_offset()
+    }
+
+    /// RFC 3339 or RFC 2822, as found in feeds.
+    #[rhai_fn(name = "parse_datetime", return_raw)]
+    pub fn parse_datetime(text: &str) -> Result<DateTime, Box<EvalAltResult>> {
+        let text = text.trim();
+        DateTime::parse_from_rfc3339(text)
+            .or_else(|_| DateTime::parse_from_rfc2822(text))
+            .map_err(|err| failed("parse_datetime", err))
+    }
+
+    /// A chrono pattern, times without an offset are taken as UTC.
+    #[rhai_fn(name = "parse_datetime", return_raw)]
+    pub fn parse_datetime_with(text: &str, fmt: &str) -> Result<DateTime, Box<EvalAltResult>> {
+        DateTime::parse_from_str(text.trim(), fmt)
+            .or_else(|_| {
+                NaiveDateTime::parse_from_str(text.trim(), fmt)
+                    .map(|naive| naive.and_utc().fixed_offset())
+            })
+            .map_err(|err| failed("parse_datetime", err))
+    }
+
+    #[rhai_fn(name = "format")]
+    pub fn format(dt: &mut DateTime, fmt: &str) -> ImmutableString {
+        dt.format(fmt).to_string().into()
+    }
+
+    #[rhai_fn(name = "to_rfc2822")]
+    pub fn to_rfc2822(dt: &mut DateTime) -> ImmutableString {
+        dt.to_rfc2822().into()
+    }
+
+    #[rhai_fn(name = "to_rfc3339")]
+    pub fn to_rfc3339(dt: &mut DateTime) -> ImmutableString {
+        dt.to_rfc3339().into()
+    }
+
+    #[rhai_fn(name = "to_string", name = "to_debug")]
+    pub fn datetime_to_string(dt: &mut DateTime) -> ImmutableString {
+        dt.to_rfc3339().into()
+    }
+
+    #[rhai_fn(get = "year")]
+    pub fn get_year(dt: &mut DateTime) -> INT {
+        chrono::Datelike::year(dt) as INT
+    }
+
+    #[rhai_fn(get = "month")]
+    pub fn get_month(dt: &mut DateTime) -> INT {
+        chrono::Datelike::month(dt) as INT
+    }
+
+    #[rhai_fn(get = "day")]
+    pub fn get_day(dt: &mut DateTime) -> INT {
+        chrono::Datelike::day(dt) as INT
+    }
+
+    #[rhai_fn(get = "hour")]
+    pub fn get_hour(dt: &mut DateTime) -> INT {
+        chrono::Timelike::hour(dt) as INT
+    }
+
+    #[rhai_fn(get = "minute")]
+    pub fn get_minute(dt: &mut DateTime) -> INT {
+        chrono::Timelike::minute(dt) as INT
+    }
+
+    #[rhai_fn(get = "second")]
+    pub fn get_second(dt: &mut DateTime) -> INT {
+        chrono::Timelike::second(dt) as INT
+    }
+
+    /// 1 for Monday through 7 for Sunday.
+    #[rhai_fn(get = "weekday")]
+    pub fn get_weekday(dt: &mut DateTime) -> INT {
+        chrono::Datelike::weekday(dt).number_from_monday() as INT
+    }
+
+    #[rhai_fn(get = "is_weekend")]
+    pub fn get_is_weekend(dt: &mut DateTime) -> bool {
+        get_weekday(dt) >= 6
+    }
+
+    #[rhai_fn(get = "timestamp")]
+    pub fn get_timestamp(dt: &mut DateTime) -> INT {
+        dt.timestamp()
+    }
+
+    #[rhai_fn(name = "to_utc")]
+    pub fn to_utc(dt: &mut DateTime) -> DateTime {
+        dt.with_timezone(&Utc).fixed_offset()
+    }
+
+    #[rhai_fn(name = "to_local")]
+    pub fn to_local(dt: &mut DateTime) -> DateTime {
+        dt.with_timezone(&Local).fixed_offset()
+    }
+
+    /// `"UTC"`, `"local"`, an offset like `"+08:00"` or an IANA name like `"Asia/Shanghai"`.
+    #[rhai_fn(name = "to_timezone", return_raw)]
+    pub fn to_timezone(dt: &mut DateTime, tz: &str) -> Result<DateTime, Box<EvalAltResult>> {
+        match tz {
+            "UTC" | "utc" | "Z" => Ok(to_utc(dt)),
+            "local" | "Local" => Ok(to_local(dt)),
+            tz => match tz.parse::<FixedOffset>() {
+                Ok(offset) => Ok(dt.with_timezone(&offset)),
+                Err(_) => tz
+                    .parse::<chrono_tz::Tz>()
+                    .map(|tz| dt.with_timezone(&tz).fixed_offset())
+                    .map_err(|err| failed("to_timezone", err)),
+            },
+        }
+    }
+
+    #[rhai_fn(name = "seconds")]
+    pub fn seconds(n: INT) -> Duration {
+        Duration::seconds(n)
+    }
+
+    #[rhai_fn(name = "minutes")]
+    pub fn minutes(n: INT) -> Duration {
+        Duration::minutes(n)
+    }
+
+    #[rhai_fn(name = "hours")]
+    pub fn hours(n: INT) -> Duration {
+        Duration::hours(n)
+    }
+
+    #[rhai_fn(name = "days")]
+    pub fn days(n: INT) -> Duration {
+        Duration::days(n)
+    }
+
+    #[rhai_fn(name = "weeks")]
+    pub fn weeks(n: INT) -> Duration {
+        Duration::weeks(n)
+    }
+
+    #[rhai_fn(get = "seconds")]
+    pub fn get_seconds(d: &mut Duration) -> INT {
+        d.num_seconds()
+    }
+
+    #[rhai_fn(get = "minutes")]
+    pub fn get_minutes(d: &mut Duration) -> INT {
+        d.num_minutes()
+    }
+
+    #[rhai_fn(get = "hours")]
+    pub fn get_hours(d: &mut Duration) -> INT {
+        d.num_hours()
+    }
+
+    #[rhai_fn(get = "days")]
+    pub fn get_days(d: &mut Duration) -> INT {
+        d.num_days()
+    }
+
+    #[rhai_fn(name = "to_string", name = "to_debug")]
+    pub fn duration_to_string(d: &mut Duration) -> ImmutableString {
+        d.to_string().into()
+    }
+
+    #[rhai_fn(name = "+", return_raw)]
+    pub fn add(dt: DateTime, d: Duration) -> Result<DateTime, Box<EvalAltResult>> {
+        dt.checked_add_signed(d)
+            .ok_or_else(|| failed("+", "date out of range"))
+    }
+
+    #[rhai_fn(name = "-", return_raw)]
+    pub fn sub(dt: DateTime, d: Duration) -> Result<DateTime, Box<EvalAltResult>> {
+        dt.checked_sub_signed(d)
+            .ok_or_else(|| failed("-", "date out of range"))
+    }
+
+    #[rhai_fn(name = "-")]
+    pub fn since(a: DateTime, b: DateTime) -> Duration {
+        a.signed_duration_since(b)
+    }
+
+    #[rhai_fn(name = "+")]
+    pub fn add_durations(a: Duration, b: Duration) -> Duration {
+        a + b
+    }
+
+    #[rhai_fn(name = "-")]
+    pub fn sub_durations(a: Duration, b: Duration) -> Duration {
+        a - b
+    }
+
+    #[rhai_fn(name = "==")]
+    pub fn eq(a: DateTime, b: DateTime) -> bool {
+        a == b
+    }
+
+    #[rhai_fn(name = "!=")]
+    pub fn ne(a: DateTime, b: DateTime) -> bool {
+        a != b
+    }
+
+    #[rhai_fn(name = "<")]
+    pub fn lt(a: DateTime, b: DateTime) -> bool {
+        a < b
+    }
+
+    #[rhai_fn(name = "<=")]
+    pub fn le(a: DateTime, b: DateTime) -> bool {
+        a <= b
+    }
+
+    #[rhai_fn(name = ">")]
+    pub fn gt(a: DateTime, b: DateTime) -> bool {
+        a > b
+    }
+
+    #[rhai_fn(name = ">=")]
+    pub fn ge(a: DateTime, b: DateTime) -> bool {
+        a >= b
+    }
+
+    #[rhai_fn(name = "==")]
+    pub fn duration_eq(a: Duration, b: Duration) -> bool {
+        a == b
+    }
+
+    #[rhai_fn(name = "!=")]
+    pub fn duration_ne(a: Duration, b: Duration) -> bool {
+        a != b
+    }
+
+    #[rhai_fn(name = "<")]
+    pub fn duration_lt(a: Duration, b: Duration) -> bool {
+        a < b
+    }
+
+    #[rhai_fn(name = "<=")]
+    pub fn duration_le(a: Duration, b: Duration) -> bool {
+        a <= b
+    }
+
+    #[rhai_fn(name = ">")]
+    pub fn duration_gt(a: Duration, b: Duration) -> bool {
+        a > b
+    }
+
+    #[rhai_fn(name = ">=")]
+    pub fn duration_ge(a: Duration, b: Duration) -> bool {
+        a >= b
+    }
+
+    /// Like "3 hours ago" or "in 2 days".
+    #[rhai_fn(name = "relative")]
+    pub fn relative(dt: &mut DateTime) -> ImmutableString {
+        relative_to(dt, now())
+    }
+
+    #[rhai_fn(name = "relative")]
+    pub fn relative_to(dt: &mut DateTime, now: DateTime) -> ImmutableString {
+        let delta = now.signed_duration_since(*dt);
+        let seconds = delta.num_seconds().abs();
+        let (n, unit) = match seconds {
+            0..=44 => return "just now".into(),
+            45..=3599 => ((seconds + 30) / 60, "minute"),
+            3600..=86399 => ((seconds + 1800) / 3600, "hour"),
+            86400..=2591999 => ((seconds + 43200) / 86400, "day"),
+            2592000..=31535999 => (seconds / 2592000, "month"),
+            _ => (seconds / 31536000, "year"),
+        };
+        let n = n.max(1);
+        let amount = match n {
+            1 => format!("1 {}", unit),
+            n => format!("{} {}s", n, unit),
+        };
+        if delta.num_seconds() < 0 {
+            format!("in {}", amount).into()
+        } else {
+            format!("{} ago", amount).into()
+        }
+    }
+}
+
+#[export_module]
+mod html_module {
+    use crate::search::tokenize;
+    use crate::utils::{strip_tags, truncate_html};
+    use rhai::{Array, Dynamic, EvalAltResult, ImmutableString, Map, Position, INT};
+    use scraper::{Html, Selector};
+
+    fn selector(selector: &str) -> Result<Selector, Box<EvalAltResult>> {
+        Selector::parse(selector).map_err(|err| {
+            EvalAltResult::ErrorInFunctionCall(
+                "select".to_string(),
+                format!("invalid selector {}: {:?}", selector, err),
+                "".into(),
+                Position::NONE,
+            )
+                .into()
+        })
+    }
+
+    /// Every element matching `selector`, as maps of `name`, `text`, `html` and `attrs`.
+    #[rhai_fn(name = "select", return_raw)]
+    pub fn select(html: &str, selector: &str) -> Result<Array, Box<EvalAltResult>> {
+        let selector = self::selector(selector)?;
+        let fragment = Html::parse_fragment(html);
+        let elements = fragment.select(&selector).map(|element| {
+            let attrs: Map = element
+                .value()
+                .attrs()
+                .map(|(key, value)| (key.into(), Dynamic::from(value.to_string())))
+                .collect();
+            let mut map = Map::new();
+            map.insert("name".into(), element.value().name().to_string().into());
+            map.insert("text".into(), element.text().collect::<String>().into());
+            map.insert("html".into(), element.inner_html().into());
+            map.insert("attrs".into(), attrs.into());
+            Dynamic::from(map)
+        });
+        Ok(elements.collect())
+    }
+
+    #[rhai_fn(name = "select_text", return_raw)]
+    pub fn select_text(html: &str, selector: &str) -> Result<Array, Box<EvalAltResult>> {
+        let selector = self::selector(selector)?;
+        let fragment = Html::parse_fragment(html);
+        let texts = fragment
+            .select(&selector)
+            .map(|element| Dynamic::from(element.text().collect::<String>()));
+        Ok(texts.collect())
+    }
+
+    /// `attr` of every element matching `selector` that has it.
+    #[rhai_fn(name = "select_attr", return_raw)]
+    pub fn select_attr(
+        html: &str,
+        selector: &str,
+        attr: &str,
+    ) -> Result<Array, Box<EvalAltResult>> {
+        let selector = self::selector(selector)?;
+        let fragment = Html::parse_fragment(html);
+        let values = fragment
+            .select(&selector)
+            .filter_map(|element| element.value().attr(attr))
+            .map(|value| Dynamic::from(value.to_string()));
+        Ok(values.collect())
+    }
+
+    #[rhai_fn(name = "strip_tags")]
+    pub fn html_strip_tags(html: &str) -> ImmutableString {
+        strip_tags(html).into()
+    }
+
+    /// `src` of the first `<img>`, or `""`.
+    #[rhai_fn(name = "first_image")]
+    pub fn first_image(html: &str) -> ImmutableString {
+        let fragment = Html::parse_fragment(html);
+        let img = Selector::parse("img[src]").unwrap();
+        fragment
+            .select(&img)
+            .find_map(|element| element.value().attr("src"))
+            .unwrap_or_default()
+            .into()
+    }
+
+    /// Words of the text, each CJK character counts as one.
+    #[rhai_fn(name = "word_count")]
+    pub fn word_count(html: &str) -> INT {
+        tokenize(&strip_tags(html)).len() as INT
+    }
+
+    #[rhai_fn(name = "truncate_html")]
+    pub fn html_truncate(html: &str, max_chars: INT) -> ImmutableString {
+        truncate_html(html, max_chars.max(0) as usize).into()
+    }
+}
+
+#[export_module]
+mod json_module {
+    use rhai::serde::{from_dynamic, to_dynamic};
+    use rhai::{Dynamic, EvalAltResult, ImmutableString, Position};
+
+    fn failed(name: &str, err: impl ToString) -> Box<EvalAltResult> {
+        EvalAltResult::ErrorInFunctionCall(
+            name.to_string(),
+            err.to_string(),
+            "".into(),
+            Position::NONE,
+        )
+            .into()
+    }
+
+    /// Any JSON value, unlike `parse_json` which only takes objects.
+    #[rhai_fn(name = "json_parse", return_raw)]
+    pub fn json_parse(text: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+        let value: serde_json::Value =
+            serde_json::from_str(text).map_err(|err| failed("json_parse", err))?;
+        to_dynamic(value)
+    }
+
+    #[rhai_fn(name = "json_stringify", return_raw)]
+    pub fn json_stringify(value: Dynamic) -> Result<ImmutableString, Box<EvalAltResult>> {
+        json_stringify_pretty(value, false)
+    }
+
+    #[rhai_fn(name = "json_stringify", return_raw)]
+    pub fn json_stringify_pretty(
+        value: Dynamic,
+        pretty: bool,
+    ) -> Result<ImmutableString, Box<EvalAltResult>> {
+        let value: serde_json::Value = from_dynamic(&value)?;
+        let text = if pretty {
+            serde_json::to_string_pretty(&value)
+        } else {
+            serde_json::to_string(&value)
+        };
+        text.map(Into::into)
+            .map_err(|err| failed("json_stringify", err))
+    }
+}
+
+#[export_module]
+mod collections_module {
+    use crate::rhai_ext::{compare_values, property, push_grouped};
+    use rhai::{Array, Dynamic, EvalAltResult, FnPtr, Map, NativeCallContext, INT};
+
+    /// Maps in `array` ordered by their `key` property.
+    #[rhai_fn(name = "sort_by_key")]
+    pub fn sort_by_key(array: Array, key: &str) -> Array {
+        let mut array = array;
+        array.sort_by(|a, b| compare_values(&property(a, key), &property(b, key)));
+        array
+    }
+
+    /// `array` split up by the `key` property of its maps, items without it are left out.
+    #[rhai_fn(name = "group_by")]
+    pub fn group_by(array: Array, key: &str) -> Map {
+        let mut groups = Map::new();
+        for item in array {
+            let value = property(&item, key);
+            if !value.is_unit() {
+                push_grouped(&mut groups, value.to_string(), item);
+            }
+        }
+        groups
+    }
+
+    /// `array` split up by what `f` returns for each item.
+    #[rhai_fn(name = "group_by", return_raw)]
+    pub fn group_by_fn(
+        ctx: NativeCallContext,
+        array: Array,
+        f: FnPtr,
+    ) -> Result<Map, Box<EvalAltResult>> {
+        let mut groups = Map::new();
+        for item in array {
+            let value: Dynamic = f.call_within_context(&ctx, (item.clone(),))?;
+            push_grouped(&mut groups, value.to_string(), item);
+        }
+        Ok(groups)
+    }
+
+    fn group_len(group: Dynamic) -> INT {
+        group.into_array().map_or(0, |group| group.len() as INT)
+    }
+
+    /// How many maps in `array` have each value of the `key` property.
+    #[rhai_fn(name = "count_by")]
+    pub fn count_by(array: Array, key: &str) -> Map {
+        group_by(array, key)
+            .into_iter()
+            .map(|(key, group)| (key, Dynamic::from_int(group_len(group))))
+            .collect()
+    }
+
+    #[rhai_fn(name = "count_by", return_raw)]
+    pub fn count_by_fn(
+        ctx: NativeCallContext,
+        array: Array,
+        f: FnPtr,
+    ) -> Result<Map, Box<EvalAltResult>> {
+        Ok(group_by_fn(ctx, array, f)?
+            .into_iter()
+            .map(|(key, group)| (key, Dynamic::from_int(group_len(group))))
+            .collect())
+    }
+}
+
 #[export_module]
 mod regex_module {
     use crate::rhai_ext::RhaiMatch;
@@ -149,3 +931,91 @@ mod regex_module {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datetime() {
+        let engine = engine(&Default::default());
+        let eval = |script: &str| engine.eval::<Dynamic>(script).unwrap().to_string();
+
+        let script = r#"
+            let published = parse_datetime("Sat, 07 Jan 2023 10:30:00 +0000");
+            [published.weekday, published.is_weekend, published.to_timezone("+08:00").hour]
+        "#;
+        assert_eq!(eval(script), "[6, true, 18]");
+        let script = r#"
+            let published = parse_datetime("2023-01-07T10:30:00Z");
+            let later = published + hours(3) + minutes(10);
+            [(later - published).minutes, later > published, later.format("%F %H:%M")]
+        "#;
+        assert_eq!(eval(script), r#"[190, true, "2023-01-07 13:40"]"#);
+        let script = r#"
+            let now = parse_datetime("2023-01-07 12:00", "%Y-%m-%d %H:%M");
+            [(now - hours(3)).relative(now), (now + days(2)).relative(now), now.relative(now)]
+        "#;
+        assert_eq!(eval(script), r#"["3 hours ago", "in 2 days", "just now"]"#);
+        assert!(engine.eval::<Dynamic>(r#"parse_datetime("yesterday")"#).is_err());
+        let script = r#"
+            let winter = parse_datetime("2023-01-07T10:30:00Z");
+            let summer = parse_datetime("2023-07-07T10:30:00Z");
+            [winter.to_timezone("Europe/Berlin").hour, summer.to_timezone("Europe/Berlin").hour]
+        "#;
+        assert_eq!(eval(script), "[11, 12]");
+        assert!(engine
+            .eval::<Dynamic>(r#"now().to_timezone("Mars/Olympus_Mons")"#)
+            .is_err());
+    }
+
+    #[test]
+    fn test_html() {
+        let engine = engine(&Default::default());
+        let eval = |script: &str| engine.eval::<Dynamic>(script).unwrap().to_string();
+
+        let html = r#"let html = "<p>Read <a href='/a' class='x'>the paper</a></p><img src='fig.png'><p>大模型</p>";"#;
+        let script = format!(
+            "{} [select_text(html, \"a.x\"), select_attr(html, \"a\", \"href\"), select(html, \"a\")[0].attrs.class]",
+            html
+        );
+        assert_eq!(eval(&script), r#"[["the paper"], ["/a"], "x"]"#);
+        let script = format!(
+            "{} [strip_tags(html), first_image(html), word_count(html), truncate_html(\"<p>Read <b>the paper</b></p>\", 8)]",
+            html
+        );
+        assert_eq!(
+            eval(&script),
+            r#"["Read the paper 大模型", "fig.png", 6, "<p>Read <b>the…</b></p>"]"#
+        );
+        assert!(engine.eval::<Dynamic>(r#"select("", "a[")"#).is_err());
+    }
+
+    #[test]
+    fn test_collections() {
+        let engine = engine(&Default::default());
+        let eval = |script: &str| engine.eval::<Dynamic>(script).unwrap().to_string();
+
+        let items = r#"let items = json_parse(`[
+            {"source": "HN", "points": 30},
+            {"source": "arXiv", "points": 5},
+            {"source": "HN", "points": 120},
+            {"points": 1}
+        ]`);"#;
+        let script = format!(
+            "{} sort_by_key(items, \"points\").map(|item| item.points)",
+            items
+        );
+        assert_eq!(eval(&script), "[1, 5, 30, 120]");
+        let script = format!("{} count_by(items, \"source\")", items);
+        assert_eq!(eval(&script), r#"#{"HN": 2, "arXiv": 1}"#);
+        let script = format!(
+            "{} group_by(items, |item| item.points > 10).keys()",
+            items
+        );
+        assert_eq!(eval(&script), r#"["false", "true"]"#);
+        let script = r#"json_stringify(json_parse(`{"a": [1, "b", null]}`))"#;
+        assert_eq!(eval(script), r#"{"a":[1,"b",null]}"#);
+        assert!(engine.eval::<Dynamic>("json_parse(`{`)").is_err());
+    }
+}
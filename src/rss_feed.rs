@@ -1,15 +1,22 @@
-use crate::config::Config;
+use crate::config::{Config, FilterConfig, Source};
+use crate::discover;
+use crate::extract;
+use crate::filter::Filter;
+use crate::hooks::Hooks;
+use crate::metrics::METRICS;
+use crate::utils::{safe_url, sanitize_html};
 use chrono::{DateTime, Duration, Utc};
 use clap::crate_version;
-use reqwest::Client;
+use reqwest::{Client, ClientBuilder};
 use rss::Channel;
 use serde::{Deserialize, Serialize};
 use std::cmp::Reverse;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Debug, Display};
 use std::fs;
 use std::fs::File;
 use std::path::Path;
+use std::time::Instant;
 use tracing::{info, warn};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,28 +41,44 @@ pub struct Rss {
     pub(crate) project_version: String,
     pub(crate) project_homepage: String,
     pub(crate) days: Vec<DailyRss>,
+    /// Feed urls found for sources that are websites, keyed by the website url.
+    #[serde(default)]
+    pub(crate) discovered: BTreeMap<String, String>,
 }
 
 impl DailyRss {
-    pub async fn new<'t, T: reqwest::IntoUrl + ToString + Clone + Debug + Display>(
-        urls: &'t [T],
+    pub async fn new(
+        sources: &[Source],
+        filters: &FilterConfig,
         client: &Client,
-    ) -> Result<DailyRss, Box<dyn std::error::Error>>
-    where
-        &'t T: 't + Display,
-    {
+        discovered: &mut BTreeMap<String, String>,
+    ) -> Result<DailyRss, Box<dyn std::error::Error>> {
+        let global = Filter::new(filters)?;
         let mut channels = vec![];
-        for url in urls {
+        for source in sources {
+            let url = source.url();
+            let filter = source.filters().map(Filter::new).transpose()?;
             info!("Feeding rss from {}", url);
-            let response = client.get(url.clone()).send().await;
-            match response {
-                Ok(content) => {
-                    let content = content.bytes().await?;
-                    let mut channel = Channel::read_from(&content[..])?;
+            let started = Instant::now();
+            let channel = fetch_channel(client, url, discovered).await;
+            match channel {
+                Ok(mut channel) => {
+                    METRICS.record_fetch(url, started.elapsed(), Some(channel.items.len()));
                     channel.link = url.to_string();
+
+                    let total = channel.items.len();
+                    channel.items.retain(|item| {
+                        global.keeps(item) && filter.as_ref().is_none_or(|f| f.keeps(item))
+                    });
+                    let filtered = total - channel.items.len();
+                    METRICS.record_filtered(url, filtered);
+                    if filtered > 0 {
+                        info!("Filtered {} of {} items from {}", filtered, total, url);
+                    }
                     channels.push(channel);
                 }
                 Err(err) => {
+                    METRICS.record_fetch(url, started.elapsed(), None);
                     warn!("Failed: {}!", err.to_string());
                 }
             };
@@ -82,17 +105,79 @@ impl DailyRss {
         }
         self.datetime
     }
+
+    /// The date the day is filed under, by when it was fetched.
+    pub fn date(&self) -> String {
+        self.datetime.format("%Y-%m-%d").to_string()
+    }
+}
+
+async fn read_channel(response: reqwest::Response) -> Result<Channel, Box<dyn std::error::Error>> {
+    let content = response.bytes().await?;
+    Ok(Channel::read_from(&content[..])?)
+}
+
+/// Fetch the feed of the source `url`, a website is resolved to its feed once and the feed url is
+/// remembered in `discovered`.
+async fn fetch_channel(
+    client: &Client,
+    url: &str,
+    discovered: &mut BTreeMap<String, String>,
+) -> Result<Channel, Box<dyn std::error::Error>> {
+    if let Some(feed) = discovered.get(url) {
+        let channel = read_channel(client.get(feed).send().await?).await;
+        if channel.is_err() {
+            // discover again next time, the site may have moved its feed
+            discovered.remove(url);
+        }
+        return channel;
+    }
+
+    let response = client.get(url).send().await?;
+    let base = response.url().clone();
+    let content = response.bytes().await?;
+    match Channel::read_from(&content[..]) {
+        Ok(channel) => Ok(channel),
+        Err(err) => {
+            let html = String::from_utf8_lossy(&content);
+            let (feed, channel) = discover::resolve(client, &base, &html).await.ok_or(err)?;
+            info!("Discovered feed {} of {}", feed, url);
+            discovered.insert(url.to_string(), feed);
+            Ok(channel)
+        }
+    }
+}
+
+/// The builder of `client`, for clients that need more settings than the configured proxy.
+pub fn client_builder(config: &Config) -> Result<ClientBuilder, reqwest::Error> {
+    let builder = reqwest::Client::builder();
+    match &config.proxy {
+        None => Ok(builder),
+        Some(scheme) => Ok(builder.proxy(reqwest::Proxy::all(scheme)?)),
+    }
+}
+
+pub fn client(config: &Config) -> Result<Client, Box<dyn std::error::Error>> {
+    info!("Building rss client!");
+    Ok(client_builder(config)?.build()?)
 }
 
 impl Rss {
+    /// `days` with the channels of days sharing a date merged into the first of them, so outputs
+    /// that go by date show each date once.
+    pub fn merged_days(&self) -> Vec<DailyRss> {
+        let mut days: Vec<DailyRss> = vec![];
+        for day in &self.days {
+            match days.iter_mut().find(|d| d.date() == day.date()) {
+                Some(merged) => merged.channels.extend(day.channels.iter().cloned()),
+                None => days.push(day.clone()),
+            }
+        }
+        days
+    }
+
     pub async fn feed_rss(config: &Config) -> Result<Rss, Box<dyn std::error::Error>> {
-        info!("Building rss client!");
-        let client = match &config.proxy {
-            None => reqwest::Client::builder().build()?,
-            Some(scheme) => reqwest::Client::builder()
-                .proxy(reqwest::Proxy::all(scheme)?)
-                .build()?,
-        };
+        let client = client(config)?;
 
         let rss: Rss = if let Some(cache_url) = &config.cache_url {
             info!("Feeding rss cache from {}", cache_url);
@@ -110,9 +195,26 @@ impl Rss {
             Default::default()
         };
 
-        let rss_items = rss.days;
+        Rss::refresh(config, &client, rss).await
+    }
+
+    /// Fetch today's feeds and merge them into `cached`, then persist the result as the new cache.
+    pub async fn refresh(
+        config: &Config,
+        client: &Client,
+        cached: Rss,
+    ) -> Result<Rss, Box<dyn std::error::Error>> {
+        let rss_items = cached.days;
+        let mut discovered = cached.discovered;
         info!("Feeding today's Rss!");
-        let today_rss: DailyRss = DailyRss::new(&config.sources, &client).await?;
+        let mut today_rss: DailyRss =
+            DailyRss::new(&config.sources, &config.filters, client, &mut discovered).await?;
+        extract::fill_content(&mut today_rss.channels, &config.sources, &rss_items, client).await;
+        if !config.hooks.is_empty() {
+            info!("Running hooks!");
+            let hooks = Hooks::new(config)?;
+            today_rss.channels = hooks.apply(today_rss.channels);
+        }
         let mut rss_items: Vec<DailyRss> = rss_items
             .into_iter()
             .filter(|x| x.datetime().date() != today_rss.datetime().date())
@@ -140,6 +242,9 @@ impl Rss {
             .map(|(datetime, mut channels)| {
                 channels.sort_by_key(|c| c.link.to_owned());
                 channels.dedup_by(|a, b| a.link == b.link);
+                for channel in &mut channels {
+                    normalise(channel, config);
+                }
                 DailyRss { datetime, channels }
             })
             .filter(|d| d.datetime() > cache_day)
@@ -151,6 +256,7 @@ impl Rss {
             project_version: crate_version!().to_string(),
             project_homepage: crate_homepage!().to_string(),
             days: rss_days,
+            discovered,
         };
 
         rss.days.sort_by_key(|x| Reverse(x.datetime()));
@@ -158,14 +264,85 @@ impl Rss {
         let cache_path = Path::new(&config.target_dir).join("cache.json");
         let mut f = File::create(cache_path)?;
         serde_json::to_writer(&mut f, &rss)?;
+        METRICS.record_refresh();
 
         Ok(rss)
     }
 }
 
+/// Clean up a fetched channel before it is cached and rendered.
+///
+/// Links that are not http(s) are dropped. Titles are plain text and stay as they are, templates
+/// escape them where they write HTML.
+fn normalise(channel: &mut Channel, config: &Config) {
+    for item in &mut channel.items {
+        item.link = item.link.as_deref().and_then(safe_url);
+    }
+    if config.allow_raw_html {
+        return;
+    }
+    channel.description = sanitize_html(&channel.description);
+    for item in &mut channel.items {
+        item.description = item.description.as_deref().map(sanitize_html);
+        item.content = item.content.as_deref().map(sanitize_html);
+    }
+}
+
 async fn feed_cache<T: reqwest::IntoUrl + Display>(
     url: T,
     client: &Client,
 ) -> Result<Rss, Box<dyn std::error::Error>> {
     Ok(client.get(url).send().await?.json().await?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rss::{ChannelBuilder, ItemBuilder};
+
+    #[test]
+    fn test_normalise() {
+        let channel = || {
+            ChannelBuilder::default()
+                .title("Tom & Jerry")
+                .items(vec![
+                    ItemBuilder::default()
+                        .title("a < b & c".to_string())
+                        .link("https://example.com/\"onmouseover=\"alert(1)".to_string())
+                        .description(
+                            r#"<p onclick="alert(1)">Hi<script>alert(1)</script></p>"#.to_string(),
+                        )
+                        .build(),
+                    ItemBuilder::default()
+                        .link("javascript:alert(1)".to_string())
+                        .build(),
+                ])
+                .build()
+        };
+
+        let mut sanitized = channel();
+        normalise(&mut sanitized, &Config::default());
+        assert_eq!(sanitized.title, "Tom & Jerry");
+        let items = &sanitized.items;
+        assert_eq!(items[0].title.as_deref(), Some("a < b & c"));
+        assert_eq!(
+            items[0].link.as_deref(),
+            Some("https://example.com/%22onmouseover=%22alert(1)")
+        );
+        assert_eq!(items[0].description.as_deref(), Some("<p>Hi</p>"));
+        assert_eq!(items[1].link, None);
+
+        let mut raw = channel();
+        let config = Config {
+            allow_raw_html: true,
+            ..Default::default()
+        };
+        normalise(&mut raw, &config);
+        assert!(raw.items[0]
+            .description
+            .as_deref()
+            .unwrap()
+            .contains("<script>"));
+        assert_eq!(raw.items[1].link, None);
+    }
+}
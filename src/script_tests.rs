@@ -0,0 +1,176 @@
+use crate::config::Config;
+use crate::render::{helper_scope, public_config};
+use crate::rhai_ext::{describe, engine, timed};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, NativeCallContext, Position, Scope, AST};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// `scripts/highlight.rhai` is tested by `scripts/highlight.test.rhai`.
+fn test_path(script_path: &str) -> PathBuf {
+    Path::new(script_path).with_extension("test.rhai")
+}
+
+fn failed(message: String) -> Box<EvalAltResult> {
+    EvalAltResult::ErrorRuntime(message.into(), Position::NONE).into()
+}
+
+fn compile(engine: &Engine, path: &Path) -> Result<AST, Box<EvalAltResult>> {
+    let mut ast = engine.compile_file(path.into())?;
+    ast.set_source(path.to_string_lossy().as_ref());
+    Ok(ast)
+}
+
+/// Every helper of `config.scripts` becomes a function of the same name, taking `params` and
+/// optionally `hash` and `root`, next to `assert(cond)`, `assert(cond, message)` and
+/// `assert_eq(left, right)`.
+fn test_engine(config: &Config) -> Result<Engine, Box<dyn std::error::Error>> {
+    let mut engine = engine(&config.rhai);
+    let public = public_config(config)?;
+
+    let mut scripts: Vec<(&String, &String)> = config.scripts.iter().collect();
+    scripts.sort();
+    for (name, path) in scripts {
+        let ast = compile(&engine, Path::new(path))
+            .map_err(|err| format!("{} ({}): {}", name, path, describe(&err)))?;
+        let (helper, config) = (format!("{} ({})", name, path), public.clone());
+        let call = move |ctx: NativeCallContext, params: Array, hash: Map, root: Dynamic| {
+            let root = if root.is_unit() { None } else { Some(root) };
+            let mut scope = helper_scope(params.into(), hash.into(), config.clone(), root);
+            ctx.engine()
+                .eval_ast_with_scope::<Dynamic>(&mut scope, &ast)
+                .map_err(|err| failed(format!("{} failed: {}", helper, describe(&err))))
+        };
+        let call = Arc::new(call);
+        let (with_hash, with_root) = (call.clone(), call.clone());
+        engine
+            .register_fn(name, move |ctx: NativeCallContext, params: Array| {
+                call(ctx, params, Map::new(), Dynamic::UNIT)
+            })
+            .register_fn(
+                name,
+                move |ctx: NativeCallContext, params: Array, hash: Map| {
+                    with_hash(ctx, params, hash, Dynamic::UNIT)
+                },
+            )
+            .register_fn(
+                name,
+                move |ctx: NativeCallContext, params: Array, hash: Map, root: Dynamic| {
+                    with_root(ctx, params, hash, root)
+                },
+            );
+    }
+
+    engine
+        .register_fn("assert", |cond: bool| match cond {
+            true => Ok(()),
+            false => Err(failed("assertion failed".to_string())),
+        })
+        .register_fn("assert", |cond: bool, message: &str| match cond {
+            true => Ok(()),
+            false => Err(failed(format!("assertion failed: {}", message))),
+        })
+        .register_fn("assert_eq", |left: Dynamic, right: Dynamic| {
+            // values of different types never print the same in debug form
+            match format!("{:?}", left) == format!("{:?}", right) {
+                true => Ok(()),
+                false => Err(failed(format!(
+                    "assertion failed: {:?} != {:?}",
+                    left, right
+                ))),
+            }
+        });
+    Ok(engine)
+}
+
+/// Run the `test_*` functions of one test file, returns how many passed and failed.
+fn run_file(engine: &Engine, path: &Path) -> (usize, usize) {
+    println!("{}", path.to_string_lossy());
+    let ast = match compile(engine, path) {
+        Ok(ast) => ast,
+        Err(err) => {
+            println!("    FAILED to compile: {}", describe(&err));
+            return (0, 1);
+        }
+    };
+    let mut tests: Vec<String> = ast
+        .iter_functions()
+        .filter(|f| f.name.starts_with("test_") && f.params.is_empty())
+        .map(|f| f.name.to_string())
+        .collect();
+    tests.sort();
+    let (mut passed, mut failed) = (0, 0);
+    for test in tests {
+        match timed(|| engine.call_fn::<Dynamic>(&mut Scope::new(), &ast, &test, ())) {
+            Ok(_) => {
+                println!("    ok {}", test);
+                passed += 1;
+            }
+            Err(err) => {
+                println!("    FAILED {}: {}", test, describe(&err));
+                failed += 1;
+            }
+        }
+    }
+    (passed, failed)
+}
+
+/// The tests of every script helper, fails if any of them does.
+pub fn run(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let engine = test_engine(config)?;
+
+    let mut scripts: Vec<(&String, &String)> = config.scripts.iter().collect();
+    scripts.sort();
+    let (mut passed, mut failed) = (0, 0);
+    for (name, path) in scripts {
+        let test_path = test_path(path);
+        if !test_path.exists() {
+            println!("{}: no tests in {}", name, test_path.to_string_lossy());
+            continue;
+        }
+        let (p, f) = run_file(&engine, &test_path);
+        passed += p;
+        failed += f;
+    }
+
+    println!("{} passed; {} failed", passed, failed);
+    match failed {
+        0 => Ok(()),
+        failed => Err(format!("{} script tests failed", failed).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_run() {
+        let dir = std::env::temp_dir().join("notfeed-script-tests");
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("shout.rhai");
+        fs::write(&script, "params[0].to_upper() + (hash.mark ?? \"!\")").unwrap();
+        fs::write(
+            dir.join("shout.test.rhai"),
+            r#"
+            fn test_plain() { assert_eq(shout(["hi"]), "HI!"); }
+            fn test_hash() { assert_eq(shout(["hi"], #{ mark: "?" }), "HI?"); }
+            fn test_wrong() {
+                assert_eq(shout(["hi"]), "hi");
+            }
+            "#,
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config
+            .scripts
+            .insert("shout".to_string(), script.to_string_lossy().to_string());
+
+        let engine = test_engine(&config).unwrap();
+        assert_eq!(
+            run_file(&engine, &test_path(&script.to_string_lossy())),
+            (2, 1)
+        );
+        assert!(run(&config).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use crate::rss_feed::Rss;
+use crate::utils::strip_tags;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+/// What a search hit shows, the text itself only lives in the index.
+#[derive(Debug, Clone, Serialize)]
+pub struct Document {
+    pub(crate) title: String,
+    pub(crate) link: String,
+    pub(crate) source: String,
+    pub(crate) date: String,
+}
+
+/// Inverted index over item titles, descriptions and source names, newest items first.
+///
+/// `build` writes it as `search.json`, which the lookup in `index.js` reads.
+#[derive(Debug, Default, Serialize)]
+pub struct SearchIndex {
+    docs: Vec<Document>,
+    index: BTreeMap<String, Vec<usize>>,
+}
+
+/// CJK text has no spaces, so every ideograph, kana or hangul syllable is a token of its own.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{ac00}'..='\u{d7af}')
+}
+
+/// Lowercased words, keep in sync with `tokenize` in `index.js`.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut word = String::new();
+    for c in text.to_lowercase().chars() {
+        if is_cjk(c) {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            tokens.push(c.to_string());
+        } else if c.is_alphanumeric() {
+            word.push(c);
+        } else if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+impl SearchIndex {
+    pub fn new(rss: &Rss) -> SearchIndex {
+        let mut search = SearchIndex::default();
+        let mut seen = HashSet::new();
+        for day in &rss.days {
+            let date = day.datetime().format("%Y-%m-%d").to_string();
+            for channel in &day.channels {
+                let source = strip_tags(&channel.title);
+                for item in &channel.items {
+                    let link = item.link.clone().unwrap_or_default();
+                    // feeds keep listing the same items for days, only the newest copy is indexed
+                    if !link.is_empty() && !seen.insert(link.clone()) {
+                        continue;
+                    }
+                    let id = search.docs.len();
+                    let title = item.title.as_deref().map(strip_tags).unwrap_or_default();
+                    let description = item
+                        .description
+                        .as_deref()
+                        .map(strip_tags)
+                        .unwrap_or_default();
+                    let tokens: BTreeSet<String> = [&title, &description, &source]
+                        .iter()
+                        .flat_map(|text| tokenize(text))
+                        .collect();
+                    for token in tokens {
+                        search.index.entry(token).or_default().push(id);
+                    }
+                    search.docs.push(Document {
+                        title,
+                        link,
+                        source: source.clone(),
+                        date: date.clone(),
+                    });
+                }
+            }
+        }
+        search
+    }
+
+    /// Items matching every word of `query`, the last word may be a prefix.
+    pub fn search(&self, query: &str) -> Vec<&Document> {
+        let terms = tokenize(query);
+        let mut matched: Option<BTreeSet<usize>> = None;
+        for (i, term) in terms.iter().enumerate() {
+            let ids: BTreeSet<usize> = if i + 1 == terms.len() {
+                self.index
+                    .range(term.clone()..)
+                    .take_while(|(token, _)| token.starts_with(term.as_str()))
+                    .flat_map(|(_, ids)| ids.iter().copied())
+                    .collect()
+            } else {
+                self.index
+                    .get(term)
+                    .map(|ids| ids.iter().copied().collect())
+                    .unwrap_or_default()
+            };
+            matched = Some(match matched {
+                None => ids,
+                Some(matched) => matched.intersection(&ids).copied().collect(),
+            });
+        }
+        matched
+            .unwrap_or_default()
+            .into_iter()
+            .map(|id| &self.docs[id])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rss_feed::DailyRss;
+    use chrono::Utc;
+    use rss::{ChannelBuilder, ItemBuilder};
+
+    #[test]
+    fn test_search() {
+        let item = |title: &str, link: &str| {
+            ItemBuilder::default()
+                .title(title.to_string())
+                .link(link.to_string())
+                .description("<p>Neural <b>machine</b> translation</p>".to_string())
+                .build()
+        };
+        let channel = ChannelBuilder::default()
+            .title("arXiv cs.CL")
+            .items(vec![
+                item(
+                    "Attention Is All You Need",
+                    "https://arxiv.org/abs/1706.03762",
+                ),
+                item("大型语言模型综述", "https://arxiv.org/abs/2303.18223"),
+            ])
+            .build();
+        let rss = Rss {
+            days: vec![DailyRss {
+                datetime: Utc::now(),
+                channels: vec![channel],
+            }],
+            ..Default::default()
+        };
+        let search = SearchIndex::new(&rss);
+
+        assert_eq!(search.search("attention ne").len(), 1);
+        assert_eq!(search.search("machine translation").len(), 2);
+        assert_eq!(search.search("arxiv").len(), 2);
+        assert_eq!(
+            search.search("语言模型")[0].link,
+            "https://arxiv.org/abs/2303.18223"
+        );
+        assert!(search.search("attention 语言").is_empty());
+        assert!(search.search("").is_empty());
+    }
+}
@@ -0,0 +1,1292 @@
+use crate::admin;
+use crate::api;
+use crate::compress::{negotiate, Encoded};
+use crate::config::{Config, OVERLAY_FILE};
+use crate::marks::Marks;
+use crate::media;
+use crate::metrics::METRICS;
+use crate::render::handlebars;
+use crate::rss_feed::{client, client_builder, Rss};
+use crate::search::SearchIndex;
+use crate::utils::{is_public_ip, token_eq, STATIC_CSS_SRC, STATIC_ICO_SRC, STATIC_JS_SRC};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use handlebars::Handlebars;
+use html_escape::{encode_double_quoted_attribute, encode_text};
+use notify::{Event as WatchEvent, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::BroadcastStream;
+#[cfg(unix)]
+use tokio_stream::wrappers::UnixListenerStream;
+use tokio_stream::StreamExt;
+use tracing::{info, warn};
+use warp::http::header::{
+    ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE, WWW_AUTHENTICATE,
+};
+use warp::http::{Response, StatusCode};
+use warp::hyper::body::{Body, Bytes};
+use warp::sse::Event;
+use warp::{self, Filter, Reply};
+
+const CONFIG_FILES: &[&str] = &["Config.yaml", "Config.toml", OVERLAY_FILE];
+
+/// Hits listed on the `/search` page, the API pages through all of them.
+const SEARCH_PAGE_LIMIT: usize = 100;
+
+/// Largest media `/media` streams, a longer response is cut off.
+const MAX_MEDIA_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+
+const LIVERELOAD_SCRIPT: &str = r#"<script>new EventSource("/__livereload").onmessage = function () { location.reload(); };</script>"#;
+
+/// Everything `serve` renders from, shared between the routes and the background tasks.
+///
+/// Every lock only guards swapping an `Arc`, readers clone it out and render from that snapshot,
+/// so no lock is ever held while rendering or while another one is taken.
+pub struct State {
+    config: RwLock<Arc<Config>>,
+    hbs: RwLock<Arc<Handlebars<'static>>>,
+    rss: RwLock<Arc<Rss>>,
+    refreshed: RwLock<DateTime<Utc>>,
+    marks: RwLock<Arc<Marks>>,
+    /// Taken by writers of `marks` only, so readers never wait for `state.json` to be written.
+    marks_writer: Mutex<()>,
+    rendered: RwLock<HashMap<String, Arc<Encoded>>>,
+    /// Bumped whenever `rendered` is cleared, a page rendered from older data is not cached.
+    generation: AtomicU64,
+    search: RwLock<Arc<SearchIndex>>,
+    /// Where the admin endpoints persist `sources`.
+    overlay: PathBuf,
+    reload: broadcast::Sender<()>,
+    watch: bool,
+}
+
+enum Change {
+    Assets,
+    Config,
+}
+
+impl State {
+    pub fn new(config: Config, hbs: Handlebars<'static>, rss: Rss, watch: bool) -> State {
+        let (reload, _) = broadcast::channel(16);
+        let marks = Marks::load(&config);
+        let search = SearchIndex::new(&rss);
+        State {
+            config: RwLock::new(Arc::new(config)),
+            hbs: RwLock::new(Arc::new(hbs)),
+            rss: RwLock::new(Arc::new(rss)),
+            refreshed: RwLock::new(Utc::now()),
+            marks: RwLock::new(Arc::new(marks)),
+            marks_writer: Mutex::new(()),
+            rendered: Default::default(),
+            generation: AtomicU64::new(0),
+            search: RwLock::new(Arc::new(search)),
+            overlay: PathBuf::from(OVERLAY_FILE),
+            reload,
+            watch,
+        }
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn hbs(&self) -> Arc<Handlebars<'static>> {
+        self.hbs.read().unwrap().clone()
+    }
+
+    pub fn rss(&self) -> Arc<Rss> {
+        self.rss.read().unwrap().clone()
+    }
+
+    pub fn overlay(&self) -> &Path {
+        &self.overlay
+    }
+
+    pub fn marks(&self) -> Arc<Marks> {
+        self.marks.read().unwrap().clone()
+    }
+
+    pub fn refreshed(&self) -> DateTime<Utc> {
+        *self.refreshed.read().unwrap()
+    }
+
+    /// Drop the rendered pages, those being rendered right now are not cached either.
+    fn invalidate(&self) {
+        let mut rendered = self.rendered.write().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        rendered.clear();
+    }
+
+    /// Render a template, cached with its encodings until the data or the templates change.
+    pub fn render(&self, name: &str) -> Arc<Encoded> {
+        let generation = self.generation.load(Ordering::SeqCst);
+        if let Some(rendered) = self.rendered.read().unwrap().get(name) {
+            return rendered.clone();
+        }
+        let (hbs, rss, marks) = (self.hbs(), self.rss(), self.marks());
+        let data = match name {
+            "starred" => marks.starred_page(&rss),
+            _ => {
+                let mut data = marks.annotate(&rss);
+                media::annotate(
+                    &mut data,
+                    &rss,
+                    &self.config().sources,
+                    Some(media::PAGE_ROUTE),
+                );
+                // index.js only asks the API of pages served here
+                data["served"] = json!(true);
+                data
+            }
+        };
+        let started = Instant::now();
+        let result = hbs.render(name, &data);
+        METRICS.record_render(name, started.elapsed());
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => return Arc::new(Encoded::new(Bytes::from(err.to_string()))),
+        };
+        let result = if self.watch {
+            inject_livereload(result)
+        } else {
+            result
+        };
+        let result = Arc::new(Encoded::new(Bytes::from(result)));
+        let mut rendered = self.rendered.write().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            rendered.insert(name.to_string(), result.clone());
+        }
+        result
+    }
+
+    /// The search index of the current feeds.
+    pub fn search_index(&self) -> Arc<SearchIndex> {
+        self.search.read().unwrap().clone()
+    }
+
+    /// Render the `/search` page for `q`.
+    fn render_search(&self, q: &str) -> String {
+        let search = self.search_index();
+        let hits = search.search(q);
+        // templates are rendered without escaping, but the query comes from the visitor
+        let results: Vec<_> = hits
+            .iter()
+            .take(SEARCH_PAGE_LIMIT)
+            .map(|doc| {
+                json!({
+                    "title": encode_text(&doc.title),
+                    "link": encode_double_quoted_attribute(&doc.link),
+                    "source": encode_text(&doc.source),
+                    "date": doc.date,
+                })
+            })
+            .collect();
+        let rss = self.rss();
+        let data = json!({
+            "site_title": rss.site_title,
+            "project_name": rss.project_name,
+            "project_version": rss.project_version,
+            "project_homepage": rss.project_homepage,
+            "q": encode_double_quoted_attribute(q),
+            "total": hits.len(),
+            "results": results,
+        });
+        let started = Instant::now();
+        let result = self.hbs().render("search", &data);
+        METRICS.record_render("search", started.elapsed());
+        match result {
+            Ok(result) if self.watch => inject_livereload(result),
+            Ok(result) => result,
+            Err(err) => err.to_string(),
+        }
+    }
+
+    /// Swap in new feeds along with their search index, and forget the read state of items that
+    /// left them.
+    pub fn set_rss(&self, rss: Rss) {
+        let search = Arc::new(SearchIndex::new(&rss));
+        *self.rss.write().unwrap() = Arc::new(rss);
+        *self.search.write().unwrap() = search;
+        *self.refreshed.write().unwrap() = Utc::now();
+        if let Err(err) = self.update_marks(|marks, rss| marks.prune(rss)) {
+            warn!("Failed: {}!", err);
+        }
+        self.invalidate();
+    }
+
+    pub fn set_config(&self, config: Config) {
+        *self.config.write().unwrap() = Arc::new(config);
+        self.invalidate();
+    }
+
+    /// Change the config, returning the changed one; nothing changes if `update` fails.
+    ///
+    /// `update` runs under the write lock, so it may persist the change before anyone sees it.
+    pub fn update_config<E>(
+        &self,
+        update: impl FnOnce(&mut Config) -> Result<(), E>,
+    ) -> Result<Arc<Config>, E> {
+        let config = {
+            let mut current = self.config.write().unwrap();
+            let mut config = Config::clone(&current);
+            update(&mut config)?;
+            *current = Arc::new(config);
+            current.clone()
+        };
+        // sources decide which media is proxied
+        self.invalidate();
+        Ok(config)
+    }
+
+    /// Change the read or starred state, persist it and drop the pages rendered with the old one.
+    ///
+    /// Nothing is saved if `update` returns `false`.
+    pub fn update_marks(
+        &self,
+        update: impl FnOnce(&mut Marks, &Rss) -> bool,
+    ) -> Result<bool, String> {
+        let _writer = self.marks_writer.lock().unwrap();
+        let rss = self.rss();
+        let mut marks = Marks::clone(&self.marks());
+        if !update(&mut marks, &rss) {
+            return Ok(false);
+        }
+        marks.prune(&rss);
+        marks.save(&self.config()).map_err(|e| e.to_string())?;
+        *self.marks.write().unwrap() = Arc::new(marks);
+        self.invalidate();
+        Ok(true)
+    }
+
+    pub fn set_hbs(&self, hbs: Handlebars<'static>) {
+        *self.hbs.write().unwrap() = Arc::new(hbs);
+        self.invalidate();
+    }
+
+    /// Why `serve` should not receive traffic yet, if anything.
+    fn unready(&self) -> Option<&'static str> {
+        if !self.hbs().has_template("index") {
+            return Some("index template is missing");
+        }
+        if METRICS.refresh_failing() {
+            return Some("latest refresh failed");
+        }
+        None
+    }
+
+    /// Pages `build` writes to `target_dir` besides the statics.
+    fn page(&self, name: &str) -> Option<Response<Body>> {
+        let config = self.config();
+        if name == config.target_name.as_deref().unwrap_or("index.html") {
+            return Some(rendered_reply(self.render("index")));
+        }
+        if name == "starred.html" {
+            return Some(rendered_reply(self.render("starred")));
+        }
+        if name == "cache.json" {
+            let rss = serde_json::to_vec(&*self.rss()).ok()?;
+            return Some(reply("application/json", rss));
+        }
+        if name == "search.json" {
+            let search = serde_json::to_vec(&*self.search_index()).ok()?;
+            return Some(reply("application/json", search));
+        }
+        None
+    }
+}
+
+/// The statics `copy_statics_to_target` falls back to when `statics_dir` lacks them.
+fn system_static(name: &str) -> Option<Response<Body>> {
+    match name {
+        "index.css" => Some(reply("text/css", STATIC_CSS_SRC)),
+        "index.js" => Some(reply("application/javascript", STATIC_JS_SRC)),
+        "favicon.ico" => Some(reply("image/x-icon", STATIC_ICO_SRC)),
+        _ => None,
+    }
+}
+
+fn reply(content_type: &str, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(body.into())
+        .unwrap()
+}
+
+/// Reply with a rendered page, `negotiate` takes its ETag and encodings from the cache.
+fn rendered_reply(page: Arc<Encoded>) -> Response<Body> {
+    let mut response = reply("text/html; charset=utf-8", page.body().clone());
+    response.extensions_mut().insert(page);
+    response
+}
+
+fn inject_livereload(html: String) -> String {
+    match html.rfind("</body>") {
+        None => html + LIVERELOAD_SCRIPT,
+        Some(pos) => format!("{}{}{}", &html[..pos], LIVERELOAD_SCRIPT, &html[pos..]),
+    }
+}
+
+/// How `serve` listens and who it lets in.
+pub struct ServeOptions {
+    pub(crate) socks: SocketAddr,
+    pub(crate) unix_socket: Option<String>,
+    pub(crate) tls: Option<(String, String)>,
+    pub(crate) basic_auth: Option<String>,
+    pub(crate) token: Option<String>,
+    pub(crate) refresh_interval: Option<Duration>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MediaQuery {
+    url: String,
+}
+
+/// The addresses the host of `url` resolves to, unless any of them is not public.
+async fn public_addrs(url: &Url) -> Result<Vec<SocketAddr>, String> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|err| format!("{}: {}", host, err))?
+            .collect(),
+    };
+    if addrs.is_empty() {
+        return Err(format!("{} has no address", url));
+    }
+    match addrs.iter().find(|addr| !is_public_ip(addr.ip())) {
+        Some(addr) => Err(format!("{} is not a public address", addr.ip())),
+        None => Ok(addrs),
+    }
+}
+
+/// Stream the media at `url` through if it belongs to a proxied source, `Range` is passed on so
+/// players can seek.
+///
+/// Only public addresses are fetched from, redirects are not followed and at most
+/// `MAX_MEDIA_SIZE` bytes are streamed, so a feed cannot point the server at internal services.
+async fn proxy_media(state: Arc<State>, url: String, range: Option<String>) -> Response<Body> {
+    let config = state.config();
+    let allowed = media::is_proxied(&state.rss(), &config.sources, &url);
+    let failed = |status, message: String| {
+        let mut response = reply("text/plain; charset=utf-8", message);
+        *response.status_mut() = status;
+        response
+    };
+    let parsed = match Url::parse(&url) {
+        Ok(parsed) if allowed => parsed,
+        _ => return failed(StatusCode::NOT_FOUND, "Not Found".to_string()),
+    };
+    let addrs = match public_addrs(&parsed).await {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            warn!("Refused: {} for {}!", err, url);
+            return failed(StatusCode::FORBIDDEN, err);
+        }
+    };
+    // the request goes to the addresses just checked, not those of another lookup
+    let mut builder = match client_builder(&config) {
+        Ok(builder) => builder.redirect(Policy::none()),
+        Err(err) => return failed(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    };
+    if let Some(domain) = parsed.domain() {
+        builder = builder.resolve_to_addrs(domain, &addrs);
+    }
+    let mut request = match builder.build() {
+        Ok(client) => client.get(parsed),
+        Err(err) => return failed(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    };
+    if let Some(range) = range {
+        request = request.header(RANGE, range);
+    }
+    let mut upstream = match request.send().await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            warn!("Failed: {} for {}!", err, url);
+            return failed(StatusCode::BAD_GATEWAY, err.to_string());
+        }
+    };
+    if upstream.status().is_redirection() {
+        return failed(
+            StatusCode::BAD_GATEWAY,
+            "redirects are not followed".to_string(),
+        );
+    }
+    if upstream
+        .content_length()
+        .is_some_and(|length| length > MAX_MEDIA_SIZE)
+    {
+        return failed(
+            StatusCode::BAD_GATEWAY,
+            format!("larger than {} bytes", MAX_MEDIA_SIZE),
+        );
+    }
+
+    let mut response = Response::builder().status(upstream.status());
+    for name in [CONTENT_TYPE, CONTENT_LENGTH, ACCEPT_RANGES, CONTENT_RANGE] {
+        if let Some(value) = upstream.headers().get(&name) {
+            response = response.header(name, value.clone());
+        }
+    }
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut streamed = 0;
+        loop {
+            match upstream.chunk().await {
+                Ok(Some(chunk)) => {
+                    streamed += chunk.len() as u64;
+                    if streamed > MAX_MEDIA_SIZE {
+                        warn!("Failed: larger than {} bytes for {}!", MAX_MEDIA_SIZE, url);
+                        sender.abort();
+                        break;
+                    }
+                    // the player went away
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("Failed: {} for {}!", err, url);
+                    sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+    response.body(body).unwrap()
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Reject requests without the configured basic auth credentials or bearer token.
+fn authorized(
+    options: &ServeOptions,
+    state: Arc<State>,
+) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    let basic = options
+        .basic_auth
+        .as_ref()
+        .map(|credentials| format!("Basic {}", STANDARD.encode(credentials)));
+    let open = basic.is_none() && options.token.is_none();
+    let token = options
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token));
+
+    warp::header::optional::<String>("authorization")
+        .and_then(move |authorization: Option<String>| {
+            // the admin token also grants access, admin endpoints need the authorization header
+            // for it; it is read per request as a config reload may change it
+            let admin_token = state
+                .config()
+                .admin_token
+                .as_ref()
+                .map(|token| format!("Bearer {}", token));
+            let allowed = open
+                || authorization.is_some_and(|authorization| {
+                    basic
+                        .iter()
+                        .chain(token.iter())
+                        .chain(admin_token.iter())
+                        .any(|a| token_eq(a, &authorization))
+                });
+            async move {
+                if allowed {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn handle_rejection(err: warp::Rejection) -> Result<Response<Vec<u8>>, warp::Rejection> {
+    if err.find::<Unauthorized>().is_none() {
+        return Err(err);
+    }
+    Ok(Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, r#"Basic realm="notfeed""#)
+        .body(b"Unauthorized".to_vec())
+        .unwrap())
+}
+
+/// Every route of `serve`, behind authorization and with caching and compression.
+fn routes(
+    state: Arc<State>,
+    options: &ServeOptions,
+) -> impl Filter<Extract = (impl Reply,), Error = warp::Rejection> + Clone {
+    let statics_dir = state.config().statics_dir.clone();
+
+    let index_state = state.clone();
+    let index = warp::get()
+        .and(warp::path::end())
+        .map(move || rendered_reply(index_state.render("index")));
+
+    let reload_state = state.clone();
+    let livereload = warp::get()
+        .and(warp::path("__livereload"))
+        .and(warp::path::end())
+        .map(move || {
+            let stream = BroadcastStream::new(reload_state.reload.subscribe())
+                .filter_map(|reload| reload.ok())
+                .map(|_| Ok::<Event, Infallible>(Event::default().data("reload")));
+            warp::sse::reply(warp::sse::keep_alive().stream(stream))
+        });
+
+    let search_state = state.clone();
+    let search = warp::get()
+        .and(warp::path("search"))
+        .and(warp::path::end())
+        .and(warp::query::<SearchQuery>())
+        .map(move |query: SearchQuery| {
+            let q = query.q.unwrap_or_default();
+            warp::reply::html(search_state.render_search(&q))
+        });
+
+    let media_state = state.clone();
+    let media_proxy = warp::get()
+        .and(warp::path("media"))
+        .and(warp::path::end())
+        .and(warp::query::<MediaQuery>())
+        .and(warp::header::optional::<String>("range"))
+        .then(move |query: MediaQuery, range: Option<String>| {
+            proxy_media(media_state.clone(), query.url, range)
+        });
+
+    let api = api::routes(state.clone());
+    let admin = admin::routes(state.clone());
+
+    let pages_state = state.clone();
+    let pages = warp::get()
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(move |name: String| {
+            let page = pages_state.page(&name);
+            async move { page.ok_or_else(warp::reject::not_found) }
+        });
+
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(|| reply("text/plain; version=0.0.4", METRICS.render()));
+
+    let static_files = warp::fs::dir(statics_dir);
+
+    let system_statics = warp::get()
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(|name: String| async move {
+            system_static(&name).ok_or_else(warp::reject::not_found)
+        });
+
+    // GET / => rendered index templates
+    // GET /__livereload => reload events of --watch
+    // GET /search?q= => search results
+    // GET /media?url= => enclosures of sources with media = "proxy"
+    // GET /api/... => JSON API
+    // POST /api/admin/... => source management, requires admin_token
+    // GET /metrics => Prometheus metrics
+    // GET /index.html, /starred.html, /cache.json, /search.json => the pages build writes
+    // GET /... => statics_dir/...
+    // GET /index.css, /index.js, /favicon.ico => embedded system statics
+    let routes = index
+        .or(livereload)
+        .or(search)
+        .or(media_proxy)
+        .or(api)
+        .or(admin)
+        .or(metrics)
+        .or(pages)
+        .or(static_files)
+        .or(system_statics);
+
+    let routes = authorized(options, state.clone())
+        .and(routes)
+        .recover(handle_rejection);
+
+    // GET /healthz, /readyz => probes, open even when the other routes need authorization
+    let healthz = warp::get()
+        .and(warp::path("healthz"))
+        .and(warp::path::end())
+        .map(|| reply("text/plain; charset=utf-8", "ok"));
+    let ready_state = state.clone();
+    let readyz = warp::get()
+        .and(warp::path("readyz"))
+        .and(warp::path::end())
+        .map(move || match ready_state.unready() {
+            None => reply("text/plain; charset=utf-8", "ok"),
+            Some(reason) => {
+                let mut response = reply("text/plain; charset=utf-8", reason);
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                response
+            }
+        });
+    let routes = healthz.or(readyz).or(routes);
+
+    warp::header::optional::<String>("accept-encoding")
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(routes)
+        .then(|accept_encoding, if_none_match, reply: _| {
+            negotiate(accept_encoding, if_none_match, Reply::into_response(reply))
+        })
+        .with(warp::log::custom(|info| {
+            METRICS.record_request(info.method().as_str(), info.status().as_u16())
+        }))
+}
+
+pub async fn serve(state: Arc<State>, options: ServeOptions) -> std::io::Result<()> {
+    if state.watch {
+        tokio::spawn(watch(state.clone()));
+    }
+    if let Some(interval) = options.refresh_interval {
+        tokio::spawn(refresh(state.clone(), interval));
+    }
+    let routes = routes(state, &options);
+
+    match (&options.unix_socket, &options.tls) {
+        (Some(_), Some(_)) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--unix-socket cannot be combined with --tls-cert/--tls-key",
+            ));
+        }
+        #[cfg(unix)]
+        (Some(path), None) => {
+            remove_stale_socket(Path::new(path))?;
+            let listener = tokio::net::UnixListener::bind(path)?;
+            info!("Listening on {}", path);
+            warp::serve(routes)
+                .run_incoming(UnixListenerStream::new(listener))
+                .await;
+        }
+        #[cfg(not(unix))]
+        (Some(_), None) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            ));
+        }
+        (None, Some((cert, key))) => {
+            info!("Listening on https://{}", options.socks);
+            warp::serve(routes)
+                .tls()
+                .cert_path(cert)
+                .key_path(key)
+                .run(options.socks)
+                .await;
+        }
+        (None, None) => {
+            info!("Listening on http://{}", options.socks);
+            warp::serve(routes).run(options.socks).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove a socket file left behind by a previous run, it would make bind fail; anything else at
+/// `path` is left alone and is an error.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.to_string_lossy()),
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+async fn refresh(state: Arc<State>, interval: Duration) {
+    let mut timer = tokio::time::interval(interval);
+    // the first tick completes immediately, but the feeds were just fetched
+    timer.tick().await;
+    loop {
+        timer.tick().await;
+        refresh_once(&state).await;
+    }
+}
+
+/// Refetch the feeds of the current config and swap them into `state`.
+pub async fn refresh_once(state: &State) {
+    info!("Refreshing feeds!");
+    let config = state.config();
+    let cached = Rss::clone(&state.rss());
+    let client = match client(&config).map_err(|e| e.to_string()) {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("Failed: {}!", err);
+            return;
+        }
+    };
+    let rss = Rss::refresh(&config, &client, cached).await;
+    match rss.map_err(|e| e.to_string()) {
+        Ok(rss) => {
+            state.set_rss(rss);
+            let _ = state.reload.send(());
+        }
+        Err(err) => {
+            METRICS.record_refresh_failure();
+            warn!("Failed: {}!", err);
+        }
+    }
+}
+
+async fn watch(state: Arc<State>) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |res: notify::Result<WatchEvent>| {
+        if let Ok(event) = res {
+            let _ = tx.send(event);
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            warn!("Failed: {}!", err.to_string());
+            return;
+        }
+    };
+    let mut watched = watch_paths(&mut watcher, &state.config());
+
+    while let Some(event) = rx.recv().await {
+        let mut events = vec![event];
+        // editors usually emit a burst of events for a single save
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+
+        let change = {
+            let config = state.config();
+            events
+                .iter()
+                .filter(|e| !matches!(e.kind, EventKind::Access(_)))
+                .flat_map(|e| &e.paths)
+                .filter_map(|p| classify(p, &config))
+                .max_by_key(|c| matches!(c, Change::Config))
+        };
+
+        match change {
+            None => continue,
+            Some(Change::Config) => {
+                info!("Config changed, reloading!");
+                let config = match Config::new() {
+                    Ok(config) => config,
+                    Err(err) => {
+                        warn!("Failed: {}!", err.to_string());
+                        continue;
+                    }
+                };
+                let cached = Rss::clone(&state.rss());
+                let rss = match client(&config).map_err(|e| e.to_string()) {
+                    Ok(client) => Rss::refresh(&config, &client, cached)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(err) => Err(err),
+                };
+                match rss {
+                    Ok(rss) => state.set_rss(rss),
+                    Err(err) => {
+                        METRICS.record_refresh_failure();
+                        warn!("Failed: {}!", err);
+                    }
+                }
+                for path in watched.drain(..) {
+                    let _ = watcher.unwatch(&path);
+                }
+                watched = watch_paths(&mut watcher, &config);
+                state.set_config(config);
+            }
+            Some(Change::Assets) => info!("Templates or statics changed, reloading!"),
+        }
+
+        let hbs = handlebars(&state.config()).map_err(|e| e.to_string());
+        match hbs {
+            Ok(hbs) => state.set_hbs(hbs),
+            Err(err) => warn!("Failed: {}!", err),
+        }
+        let _ = state.reload.send(());
+    }
+}
+
+fn absolute(path: impl AsRef<Path>) -> PathBuf {
+    std::env::current_dir()
+        .map(|cwd| cwd.join(path.as_ref()))
+        .unwrap_or_else(|_| path.as_ref().to_path_buf())
+}
+
+fn watch_paths(watcher: &mut RecommendedWatcher, config: &Config) -> Vec<PathBuf> {
+    let mut paths = vec![
+        (absolute("."), RecursiveMode::NonRecursive),
+        (absolute(&config.templates_dir), RecursiveMode::Recursive),
+        (absolute(&config.statics_dir), RecursiveMode::Recursive),
+    ];
+    // watch the parent directories, editors often replace files instead of writing them
+    for script_path in config.scripts.values() {
+        if let Some(parent) = absolute(script_path).parent() {
+            paths.push((parent.to_path_buf(), RecursiveMode::NonRecursive));
+        }
+    }
+
+    let mut watched = vec![];
+    for (path, mode) in paths {
+        if !path.exists() || watched.contains(&path) {
+            continue;
+        }
+        info!("Watching {}", path.to_string_lossy());
+        match watcher.watch(&path, mode) {
+            Ok(_) => watched.push(path),
+            Err(err) => warn!("Failed: {}!", err.to_string()),
+        }
+    }
+    watched
+}
+
+fn classify(path: &Path, config: &Config) -> Option<Change> {
+    if CONFIG_FILES.iter().any(|f| absolute(f) == path) {
+        return Some(Change::Config);
+    }
+    let assets = config
+        .scripts
+        .values()
+        .map(absolute)
+        .any(|script_path| script_path == path)
+        || path.starts_with(absolute(&config.templates_dir))
+        || path.starts_with(absolute(&config.statics_dir));
+    if assets {
+        Some(Change::Assets)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MediaMode, Source};
+    use crate::marks::item_id;
+    use crate::rss_feed::DailyRss;
+    use rss::{ChannelBuilder, EnclosureBuilder, ItemBuilder};
+    use warp::http::header::{CONTENT_ENCODING, ETAG};
+
+    fn state(name: &str) -> State {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = Config {
+            target_dir: dir.to_string_lossy().to_string(),
+            statics_dir: dir.join("statics").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let channel = ChannelBuilder::default()
+            .title("arXiv cs.CL")
+            .link("https://export.arxiv.org/rss/cs.CL")
+            .items(vec![ItemBuilder::default()
+                .title("Attention Is All You Need".to_string())
+                .link("https://arxiv.org/abs/1706.03762".to_string())
+                .build()])
+            .build();
+        let rss = Rss {
+            days: vec![DailyRss {
+                datetime: Utc::now(),
+                channels: vec![channel],
+            }],
+            ..Default::default()
+        };
+        let hbs = handlebars(&config).unwrap();
+        let mut state = State::new(config, hbs, rss, false);
+        state.overlay = dir.join(OVERLAY_FILE);
+        state
+    }
+
+    #[tokio::test]
+    async fn test_refresh() {
+        let state = state("notfeed-serve-refresh");
+        let rss = state.rss();
+        let channel = &rss.days[0].channels[0];
+        let id = item_id(channel, &channel.items[0]);
+        state
+            .update_marks(|marks, _| {
+                marks.set_read(&id, true);
+                true
+            })
+            .unwrap();
+        assert!(String::from_utf8_lossy(state.render("index").body()).contains("Attention"));
+        assert_eq!(state.search_index().search("attention").len(), 1);
+
+        // without sources the refresh only drops today's cached items
+        refresh_once(&state).await;
+        assert!(!String::from_utf8_lossy(state.render("index").body()).contains("Attention"));
+        assert!(state.search_index().search("attention").is_empty());
+        assert!(!state.marks().is_read(&id));
+        assert!(Path::new(&state.config().target_dir)
+            .join("cache.json")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let state = Arc::new(state("notfeed-serve-routes"));
+        let statics_dir = state.config().statics_dir.clone();
+        std::fs::create_dir_all(&statics_dir).unwrap();
+        std::fs::write(Path::new(&statics_dir).join("index.js"), "// custom").unwrap();
+        let options = ServeOptions {
+            socks: "127.0.0.1:8080".parse().unwrap(),
+            unix_socket: None,
+            tls: None,
+            basic_auth: None,
+            token: None,
+            refresh_interval: None,
+        };
+        let routes = routes(state, &options);
+        let get = |path: &'static str| warp::test::request().path(path).reply(&routes);
+
+        let index = get("/index.html").await;
+        assert_eq!(index.status(), StatusCode::OK);
+        assert_eq!(index.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+        assert!(String::from_utf8_lossy(index.body()).contains("Attention"));
+        assert!(String::from_utf8_lossy(index.body()).contains("<body data-served>"));
+
+        let cache = get("/cache.json").await;
+        assert_eq!(cache.status(), StatusCode::OK);
+        let rss: Rss = serde_json::from_slice(cache.body()).unwrap();
+        assert_eq!(rss.days[0].channels[0].title, "arXiv cs.CL");
+
+        let css = get("/index.css").await;
+        assert_eq!(css.status(), StatusCode::OK);
+        assert_eq!(css.headers()[CONTENT_TYPE], "text/css");
+        assert_eq!(css.body().as_ref(), STATIC_CSS_SRC.as_bytes());
+
+        // statics_dir wins over the embedded statics
+        assert_eq!(get("/index.js").await.body().as_ref(), b"// custom");
+        assert_eq!(get("/unknown.html").await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_mark_routes() {
+        let state = Arc::new(state("notfeed-serve-marks"));
+        state
+            .update_config(|config| {
+                config.admin_token = Some("secret".to_string());
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        let rss = state.rss();
+        let channel = &rss.days[0].channels[0];
+        let id = item_id(channel, &channel.items[0]);
+        let path = format!("/api/items/{}/star", id);
+        let options = ServeOptions {
+            socks: "127.0.0.1:8080".parse().unwrap(),
+            unix_socket: None,
+            tls: None,
+            basic_auth: None,
+            token: None,
+            refresh_interval: None,
+        };
+        let routes = routes(state.clone(), &options);
+        let star = |authorization: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path(&path)
+                .header("authorization", authorization)
+                .reply(&routes)
+        };
+
+        assert_eq!(star("").await.status(), StatusCode::UNAUTHORIZED);
+        assert!(!state.marks().is_starred(&id));
+        assert_eq!(star("Bearer secret").await.status(), StatusCode::OK);
+        assert!(state.marks().is_starred(&id));
+
+        state
+            .update_config(|config| {
+                config.serve.open_marks = true;
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert_eq!(star("").await.status(), StatusCode::OK);
+        assert!(!state.marks().is_starred(&id));
+    }
+
+    #[tokio::test]
+    async fn test_admin_routes() {
+        let state = Arc::new(state("notfeed-serve-admin"));
+        std::fs::create_dir_all(&state.config().target_dir).unwrap();
+        let overlay = state.overlay().to_path_buf();
+        state
+            .update_config(|config| {
+                config.admin_token = Some("secret".to_string());
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        let options = ServeOptions {
+            socks: "127.0.0.1:8080".parse().unwrap(),
+            unix_socket: None,
+            tls: None,
+            basic_auth: None,
+            token: None,
+            refresh_interval: None,
+        };
+        let routes = routes(state.clone(), &options);
+        let post = |path: &'static str, authorization: &'static str, url: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path(path)
+                .header("authorization", authorization)
+                .json(&serde_json::json!({ "url": url }))
+                .reply(&routes)
+        };
+        let add = "/api/admin/sources/add";
+        let remove = "/api/admin/sources/remove";
+        let feed = "https://example.com/feed.xml";
+
+        assert_eq!(post(add, "", feed).await.status(), StatusCode::UNAUTHORIZED);
+        let invalid = post(add, "Bearer secret", "javascript:alert(1)").await;
+        assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+        assert!(state.config().sources.is_empty());
+
+        assert_eq!(
+            post(add, "Bearer secret", feed).await.status(),
+            StatusCode::ACCEPTED
+        );
+        assert_eq!(state.config().sources[0].url(), feed);
+        assert!(std::fs::read_to_string(&overlay).unwrap().contains(feed));
+        let duplicate = post(add, "Bearer secret", feed).await;
+        assert_eq!(duplicate.status(), StatusCode::CONFLICT);
+        assert_eq!(state.config().sources.len(), 1);
+
+        assert_eq!(
+            post(remove, "Bearer secret", feed).await.status(),
+            StatusCode::ACCEPTED
+        );
+        assert!(state.config().sources.is_empty());
+        assert!(!std::fs::read_to_string(&overlay).unwrap().contains(feed));
+
+        // a failed save leaves the served sources as they were
+        std::fs::remove_file(&overlay).unwrap();
+        std::fs::create_dir(&overlay).unwrap();
+        let failed = post(add, "Bearer secret", feed).await;
+        assert_eq!(failed.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(state.config().sources.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_authorized() {
+        let state = Arc::new(state("notfeed-serve-authorized"));
+        let options = ServeOptions {
+            socks: "127.0.0.1:8080".parse().unwrap(),
+            unix_socket: None,
+            tls: None,
+            basic_auth: Some("user:password".to_string()),
+            token: Some("token".to_string()),
+            refresh_interval: None,
+        };
+        let routes = routes(state.clone(), &options);
+        let get = |authorization: String| {
+            warp::test::request()
+                .path("/index.html")
+                .header("authorization", authorization)
+                .reply(&routes)
+        };
+
+        let denied = get(String::new()).await;
+        assert_eq!(denied.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            denied.headers()[WWW_AUTHENTICATE],
+            r#"Basic realm="notfeed""#
+        );
+        let basic = format!("Basic {}", STANDARD.encode("user:password"));
+        assert_eq!(get(basic).await.status(), StatusCode::OK);
+        let wrong = format!("Basic {}", STANDARD.encode("user:wrong"));
+        assert_eq!(get(wrong).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            get("Bearer token".to_string()).await.status(),
+            StatusCode::OK
+        );
+
+        // the admin token is read per request
+        let admin = || get("Bearer secret".to_string());
+        assert_eq!(admin().await.status(), StatusCode::UNAUTHORIZED);
+        state
+            .update_config(|config| {
+                config.admin_token = Some("secret".to_string());
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert_eq!(admin().await.status(), StatusCode::OK);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = std::env::temp_dir().join("notfeed-serve-socket");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let missing = dir.join("missing.sock");
+        assert!(remove_stale_socket(&missing).is_ok());
+
+        let socket = dir.join("notfeed.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+
+        let file = dir.join("notes.txt");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert!(file.exists());
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_routes() {
+        let state = Arc::new(state("notfeed-serve-negotiate"));
+        let options = ServeOptions {
+            socks: "127.0.0.1:8080".parse().unwrap(),
+            unix_socket: None,
+            tls: None,
+            basic_auth: None,
+            token: None,
+            refresh_interval: None,
+        };
+        let routes = routes(state.clone(), &options);
+        let get = |path: &'static str, if_none_match: &str| {
+            warp::test::request()
+                .path(path)
+                .header("accept-encoding", "gzip")
+                .header("if-none-match", if_none_match)
+                .reply(&routes)
+        };
+
+        let index = get("/index.html", "").await;
+        assert_eq!(index.status(), StatusCode::OK);
+        assert_eq!(index.headers()[CONTENT_ENCODING], "gzip");
+        let etag = index.headers()[ETAG].to_str().unwrap().to_string();
+        assert!(etag.ends_with("-gzip\""));
+        // the page and its gzip variant are cached until the data changes
+        assert!(Arc::ptr_eq(&state.render("index"), &state.render("index")));
+        let again = get("/", "").await;
+        assert_eq!(again.headers()[ETAG], etag.as_str());
+        assert_eq!(again.body(), index.body());
+
+        let not_modified = get("/index.html", &etag).await;
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+        assert!(not_modified.body().is_empty());
+
+        // responses that are not cached get an ETag too
+        let status = get("/api/status", "").await;
+        assert_eq!(status.status(), StatusCode::OK);
+        let etag = status.headers()[ETAG].to_str().unwrap().to_string();
+        let not_modified = get("/api/status", &etag).await;
+        assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_probes_bypass_authorization() {
+        let state = Arc::new(state("notfeed-serve-probes"));
+        let options = ServeOptions {
+            socks: "127.0.0.1:8080".parse().unwrap(),
+            unix_socket: None,
+            tls: None,
+            basic_auth: Some("user:password".to_string()),
+            token: Some("token".to_string()),
+            refresh_interval: None,
+        };
+        let routes = routes(state.clone(), &options);
+        let get = |path: &'static str| warp::test::request().path(path).reply(&routes);
+
+        assert_eq!(get("/index.html").await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(get("/metrics").await.status(), StatusCode::UNAUTHORIZED);
+        let healthz = get("/healthz").await;
+        assert_eq!(healthz.status(), StatusCode::OK);
+        assert_eq!(healthz.body().as_ref(), b"ok");
+
+        // readiness is reported without credentials as well
+        state.set_hbs(Handlebars::new());
+        let readyz = get("/readyz").await;
+        assert_eq!(readyz.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(readyz.body().as_ref(), b"index template is missing");
+    }
+
+    #[tokio::test]
+    async fn test_media_route() {
+        let state = Arc::new(state("notfeed-serve-media"));
+        let link = "https://talk.example.com/feed.xml";
+        state
+            .update_config(|config| {
+                config.sources = vec![Source::Detailed {
+                    url: link.to_string(),
+                    full_text: false,
+                    media: MediaMode::Proxy,
+                    filters: Default::default(),
+                }];
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        let mut item = ItemBuilder::default()
+            .title("Episode 1".to_string())
+            .build();
+        item.set_enclosure(
+            EnclosureBuilder::default()
+                .url("http://127.0.0.1:9/ep1.mp3")
+                .mime_type("audio/mpeg")
+                .build(),
+        );
+        state.set_rss(Rss {
+            days: vec![DailyRss {
+                datetime: Utc::now(),
+                channels: vec![ChannelBuilder::default()
+                    .title("Talk")
+                    .link(link)
+                    .items(vec![item])
+                    .build()],
+            }],
+            ..Default::default()
+        });
+        let options = ServeOptions {
+            socks: "127.0.0.1:8080".parse().unwrap(),
+            unix_socket: None,
+            tls: None,
+            basic_auth: None,
+            token: None,
+            refresh_interval: None,
+        };
+        let routes = routes(state, &options);
+        let get = |path: &'static str| warp::test::request().path(path).reply(&routes);
+
+        // only media of proxied sources is fetched, and never from internal addresses
+        let unknown = get("/media?url=https%3A%2F%2Fcdn.example.com%2Fep1.mp3").await;
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+        let internal = get("/media?url=http%3A%2F%2F127.0.0.1%3A9%2Fep1.mp3").await;
+        assert_eq!(internal.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            internal.body().as_ref(),
+            b"127.0.0.1 is not a public address"
+        );
+    }
+}
@@ -1,10 +1,14 @@
 use crate::config::Config;
+use ego_tree::NodeRef;
 use fs_extra::copy_items;
 use fs_extra::dir::{copy, get_dir_content, CopyOptions};
 use html_minifier::{css::minify as css_minify, js::minify as js_minify};
+use reqwest::Url;
+use scraper::{Html, Node};
 use std::fs;
 use std::fs::File;
 use std::io::Write;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 
 #[macro_export]
@@ -26,11 +30,51 @@ pub(crate) const TEMPLATES_SRC: &str = include_str!(concat!(
     "/vendor/system-templates/index.hbs"
 ));
 
+pub(crate) const SEARCH_SRC: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/vendor/system-templates/search.hbs"
+));
+
+pub(crate) const STARRED_SRC: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/vendor/system-templates/starred.hbs"
+));
+
 pub(crate) const PDF_SRC: &str = include_str!(concat!(
     env!("CARGO_MANIFEST_DIR"),
     "/vendor/system-templates/pdf.tex"
 ));
 
+pub(crate) const MARKDOWN_SRC: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/vendor/system-templates/markdown.md"
+));
+
+pub(crate) const GEMINI_INDEX_SRC: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/vendor/system-templates/index.gmi"
+));
+
+pub(crate) const GEMINI_DAY_SRC: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/vendor/system-templates/day.gmi"
+));
+
+pub(crate) const MAIL_TEXT_SRC: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/vendor/system-templates/mail.txt"
+));
+
+pub(crate) const MAIL_HTML_SRC: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/vendor/system-templates/mail.html"
+));
+
+pub(crate) const EPUB_SRC: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/vendor/system-templates/epub.xhtml"
+));
+
 pub(crate) const STATIC_CSS_SRC: &str = include_str!(concat!(
     env!("CARGO_MANIFEST_DIR"),
     "/vendor/system-statics/index.css"
@@ -125,6 +169,200 @@ pub fn command_escape(text: &str) -> String {
     res
 }
 
+pub(crate) const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+pub fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn sanitize_html(html: &str) -> String {
+    ammonia::clean(html)
+}
+
+/// `url` if it is an absolute http(s) url, serialized so quotes and angle brackets are
+/// percent-encoded and it can go into an attribute as is.
+pub fn safe_url(url: &str) -> Option<String> {
+    let url = Url::parse(url.trim()).ok()?;
+    match url.scheme() {
+        "http" | "https" => Some(url.to_string()),
+        _ => None,
+    }
+}
+
+/// Whether `ip` is on the public internet, not a loopback, private, link-local or otherwise
+/// internal address a server should not be made to fetch from.
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // shared address space of carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                // benchmarking and reserved
+                || (a == 198 && (b == 18 || b == 19))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, link-local and site-local
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80
+                    || first & 0xffc0 == 0xfec0)
+            }
+        },
+    }
+}
+
+pub fn strip_tags(html: &str) -> String {
+    let fragment = Html::parse_fragment(html);
+    let text: Vec<&str> = fragment.root_element().text().collect();
+    text.join(" ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn to_xhtml(html: &str) -> String {
+    let fragment = Html::parse_fragment(html);
+    let mut res = String::new();
+    for child in fragment.root_element().children() {
+        to_xhtml_inner(child, &mut res);
+    }
+    res
+}
+
+fn to_xhtml_inner(node: NodeRef<Node>, res: &mut String) {
+    match node.value() {
+        Node::Text(text) => res.push_str(&xml_escape(text)),
+        Node::Element(element) => {
+            let name = element.name();
+            if name == "script" || name == "style" {
+                return;
+            }
+            res.push('<');
+            res.push_str(name);
+            for (key, value) in element.attrs() {
+                let valid = key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    && !key.starts_with("on");
+                if valid {
+                    res.push_str(&format!(" {}=\"{}\"", key, xml_escape(value)));
+                }
+            }
+            if VOID_ELEMENTS.contains(&name) {
+                res.push_str("/>");
+                return;
+            }
+            res.push('>');
+            for child in node.children() {
+                to_xhtml_inner(child, res);
+            }
+            res.push_str(&format!("</{}>", name));
+        }
+        _ => {}
+    }
+}
+
+/// The first `max_chars` characters of text in `html`, followed by an ellipsis if cut, with every
+/// tag that is still open closed again.
+pub fn truncate_html(html: &str, max_chars: usize) -> String {
+    let fragment = Html::parse_fragment(html);
+    let mut res = String::new();
+    let mut remaining = max_chars;
+    for child in fragment.root_element().children() {
+        if !truncate_inner(child, &mut res, &mut remaining) {
+            break;
+        }
+    }
+    res
+}
+
+/// Returns `false` once the text ran out.
+fn truncate_inner(node: NodeRef<Node>, res: &mut String, remaining: &mut usize) -> bool {
+    match node.value() {
+        Node::Text(text) => {
+            let count = text.chars().count();
+            if count <= *remaining {
+                *remaining -= count;
+                res.push_str(&xml_escape(text));
+                return true;
+            }
+            let cut: String = text.chars().take(*remaining).collect();
+            // rather end on a whole word
+            let cut = match cut.rfind(char::is_whitespace) {
+                Some(end) if end > 0 && !text[cut.len()..].starts_with(char::is_whitespace) => {
+                    &cut[..end]
+                }
+                _ => &cut[..],
+            };
+            res.push_str(&xml_escape(cut.trim_end()));
+            res.push('…');
+            *remaining = 0;
+            false
+        }
+        Node::Element(element) => {
+            let name = element.name();
+            if name == "script" || name == "style" {
+                return true;
+            }
+            res.push('<');
+            res.push_str(name);
+            for (key, value) in element.attrs() {
+                res.push_str(&format!(" {}=\"{}\"", key, xml_escape(value)));
+            }
+            res.push('>');
+            if VOID_ELEMENTS.contains(&name) {
+                return true;
+            }
+            let mut more = true;
+            for child in node.children() {
+                if !truncate_inner(child, res, remaining) {
+                    more = false;
+                    break;
+                }
+            }
+            res.push_str(&format!("</{}>", name));
+            more
+        }
+        _ => true,
+    }
+}
+
+/// 64-bit FNV-1a, unlike `DefaultHasher` its output never changes between releases.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Compare in constant time so tokens cannot be guessed byte by byte.
+pub fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
 pub fn copy_statics_to_target(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
     fs::create_dir_all(&config.target_dir)?;
     if Path::new(&config.statics_dir).exists() {
@@ -193,4 +431,46 @@ mod tests {
         assert_eq!(remove_unpair("(((()xx))", '(', ')'), "((()xx))");
         assert_eq!(remove_unpair("(())", '(', ')'), "(())");
     }
+
+    #[test]
+    fn test_safe_url() {
+        assert_eq!(
+            safe_url(" https://example.com/a b?q=\"x\"&r=<y> ").as_deref(),
+            Some("https://example.com/a%20b?q=%22x%22&r=%3Cy%3E")
+        );
+        assert_eq!(safe_url("javascript:alert(1)"), None);
+        assert_eq!(safe_url("data:text/html,<script>"), None);
+        assert_eq!(safe_url("/relative"), None);
+    }
+
+    #[test]
+    fn test_is_public_ip() {
+        let public = |ip: &str| is_public_ip(ip.parse().unwrap());
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:2800:220:1:248:1893:25c8:1946"));
+        for internal in [
+            "127.0.0.1",
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!public(internal), "{}", internal);
+        }
+    }
+
+    #[test]
+    fn test_truncate_html() {
+        let html = "<p>Attention is <b>all you need</b></p><p>More</p>";
+        assert_eq!(truncate_html(html, 17), "<p>Attention is <b>all…</b></p>");
+        assert_eq!(truncate_html(html, 100), html);
+        assert_eq!(truncate_html("<p>a<br>b &amp; c</p>", 3), "<p>a<br>b…</p>");
+    }
 }